@@ -0,0 +1,332 @@
+use std::env;
+use std::process;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use tokio::time::{self, MissedTickBehavior};
+
+use arcus_pubsub_client::{Client, Message, Result, Subscription};
+
+const USAGE: &str = "usage: arcus-pubsub-bench [options]
+
+options:
+  -a <host:port>       server address (default 127.0.0.1:11211)
+  -s <path>            unix socket path instead of tcp
+  --mode pubsub|lock   workload (default pubsub)
+  --duration <secs>    how long to drive load (default 10)
+  --channel <name>     channel to publish on (default bench)
+  -P <n>               publisher connections (default 1)
+  -S <n>               subscriber connections (default 1)
+  --size <bytes>       payload size (default 64)
+  --rate <n>           messages per second per publisher, 0 = unlimited (default 0)
+  -L <n>               lock contender connections (default 4)
+  --keys <n>           distinct lock keys shared by the contenders (default 1)
+  --hold <ms>          time each lock is held (default 1)
+  --lease <secs>       lock lease (default 5)";
+
+struct Options {
+    addr: String,
+    unix: Option<String>,
+    mode: String,
+    duration: Duration,
+    channel: String,
+    publishers: usize,
+    subscribers: usize,
+    size: usize,
+    rate: u64,
+    contenders: usize,
+    keys: usize,
+    hold: Duration,
+    lease: Duration
+}
+
+fn usage() -> ! {
+    eprintln!("{}", USAGE);
+    process::exit(1);
+}
+
+fn parse_options() -> Options {
+    let mut opts = Options {
+        addr: "127.0.0.1:11211".to_string(),
+        unix: None,
+        mode: "pubsub".to_string(),
+        duration: Duration::from_secs(10),
+        channel: "bench".to_string(),
+        publishers: 1,
+        subscribers: 1,
+        size: 64,
+        rate: 0,
+        contenders: 4,
+        keys: 1,
+        hold: Duration::from_millis(1),
+        lease: Duration::from_secs(5)
+    };
+
+    fn num<T: std::str::FromStr>(v: Option<String>) -> T {
+        return v.and_then(|v| v.parse().ok()).unwrap_or_else(|| usage());
+    }
+
+    /* Seconds as a Duration; NaN, negative or out of range values are a usage error. */
+    fn secs(v: Option<String>) -> Duration {
+        return Duration::try_from_secs_f64(num(v)).unwrap_or_else(|_| usage());
+    }
+
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-a" => opts.addr = args.next().unwrap_or_else(|| usage()),
+            "-s" => opts.unix = Some(args.next().unwrap_or_else(|| usage())),
+            "--mode" => opts.mode = args.next().unwrap_or_else(|| usage()),
+            "--duration" => opts.duration = secs(args.next()),
+            "--channel" => opts.channel = args.next().unwrap_or_else(|| usage()),
+            "-P" => opts.publishers = num(args.next()),
+            "-S" => opts.subscribers = num(args.next()),
+            "--size" => opts.size = num(args.next()),
+            "--rate" => opts.rate = num(args.next()),
+            "-L" => opts.contenders = num(args.next()),
+            "--keys" => opts.keys = num(args.next()),
+            "--hold" => opts.hold = Duration::from_millis(num(args.next())),
+            "--lease" => opts.lease = secs(args.next()),
+            _ => usage()
+        }
+    }
+    if opts.keys == 0 || (opts.mode != "pubsub" && opts.mode != "lock") {
+        usage();
+    }
+    return opts;
+}
+
+async fn connect(opts: &Options) -> Result<Client> {
+    match &opts.unix {
+        Some(path) => return Client::connect_unix(path).await,
+        None => return Client::connect(opts.addr.as_str()).await
+    }
+}
+
+/* Payloads carry `<publisher>:<seq>:<sent at, ns since start>:` padded to the requested size. */
+fn make_payload(publisher: usize, seq: u64, sent: u64, size: usize) -> String {
+    let mut payload = format!("{}:{}:{}:", publisher, seq, sent);
+    while payload.len() < size {
+        payload.push('x');
+    }
+    return payload;
+}
+
+fn parse_payload(payload: &str) -> Option<(usize, u64, u64)> {
+    let mut fields = payload.splitn(4, ':');
+    let publisher = fields.next()?.parse().ok()?;
+    let seq = fields.next()?.parse().ok()?;
+    let sent = fields.next()?.parse().ok()?;
+    return Some((publisher, seq, sent));
+}
+
+fn report_latency(name: &str, mut samples: Vec<u64>) {
+    if samples.is_empty() {
+        println!("{} latency: no samples", name);
+        return;
+    }
+    samples.sort_unstable();
+    let pct = |p: f64| samples[((samples.len() - 1) as f64 * p) as usize];
+    println!("{} latency (us): p50 {} p90 {} p99 {} p99.9 {} max {}",
+             name, pct(0.5), pct(0.9), pct(0.99), pct(0.999), samples[samples.len() - 1]);
+}
+
+struct SubscriberStats {
+    received: u64,
+    reordered: u64,
+    /* Per publisher: one past the highest sequence seen, and how many of its messages arrived. */
+    next_seq: Vec<u64>,
+    seen: Vec<u64>,
+    latencies: Vec<u64>
+}
+
+async fn run_subscriber(mut sub: Subscription, publishers: usize,
+                        start: Instant, until: Instant) -> SubscriberStats {
+    let mut stats = SubscriberStats {
+        received: 0,
+        reordered: 0,
+        next_seq: vec![0; publishers],
+        seen: vec![0; publishers],
+        latencies: Vec::new()
+    };
+
+    loop {
+        let msg = tokio::select! {
+            msg = sub.recv() => msg,
+            _ = time::sleep_until(until.into()) => None
+        };
+        let payload = match msg {
+            Some(Message::Channel { payload, .. }) => payload,
+            Some(_) => continue,
+            None => break
+        };
+        let (publisher, seq, sent) = match parse_payload(&payload) {
+            Some(p) if p.0 < publishers => p,
+            _ => continue
+        };
+
+        stats.received += 1;
+        stats.seen[publisher] += 1;
+        stats.latencies.push((start.elapsed().as_nanos() as u64).saturating_sub(sent) / 1000);
+        if seq >= stats.next_seq[publisher] {
+            stats.next_seq[publisher] = seq + 1;
+        } else {
+            stats.reordered += 1;
+        }
+    }
+    return stats;
+}
+
+async fn run_publisher(client: Client, id: usize, opts: Arc<Options>, start: Instant,
+                       until: Instant) -> Result<u64> {
+    let mut ticker = match opts.rate {
+        0 => None,
+        rate => {
+            let mut t = time::interval(Duration::from_secs_f64(1.0 / rate as f64));
+            t.set_missed_tick_behavior(MissedTickBehavior::Delay);
+            Some(t)
+        }
+    };
+
+    let mut seq = 0;
+    while Instant::now() < until {
+        if let Some(t) = ticker.as_mut() {
+            t.tick().await;
+        }
+        let payload = make_payload(id, seq, start.elapsed().as_nanos() as u64, opts.size);
+        client.publish(&opts.channel, &payload).await?;
+        seq += 1;
+    }
+    return Ok(seq);
+}
+
+async fn bench_pubsub(opts: Arc<Options>) -> Result<()> {
+    let start = Instant::now();
+    let until = start + opts.duration;
+    /* Give in-flight messages a moment to arrive before counting losses. */
+    let drain = until + Duration::from_secs(1);
+
+    let mut subscribers = Vec::new();
+    for _ in 0..opts.subscribers {
+        /* Subscribe up front so nothing published from the start is missed. */
+        let client = connect(&opts).await?;
+        let sub = client.subscribe(&[&opts.channel]).await?;
+        subscribers.push((client, tokio::spawn(run_subscriber(sub, opts.publishers, start, drain))));
+    }
+
+    let mut publishers = Vec::new();
+    for id in 0..opts.publishers {
+        let client = connect(&opts).await?;
+        publishers.push(tokio::spawn(run_publisher(client, id, opts.clone(), start, until)));
+    }
+
+    let mut published = Vec::new();
+    for p in publishers {
+        published.push(p.await.unwrap_or(Ok(0))?);
+    }
+    let elapsed = start.elapsed().as_secs_f64();
+
+    let total: u64 = published.iter().sum();
+    let (mut received, mut reordered, mut lost, mut gaps) = (0, 0, 0, 0);
+    let mut latencies = Vec::new();
+    for (_client, s) in subscribers {
+        let stats = match s.await {
+            Ok(stats) => stats,
+            Err(_) => continue
+        };
+        /*
+         * Every subscriber should have seen each publisher's sequence exactly once:
+         * holes below the highest sequence seen were lost on the way, and what is
+         * missing past it never arrived before the drain ended.
+         */
+        received += stats.received;
+        reordered += stats.reordered;
+        for (p, sent) in published.iter().enumerate() {
+            gaps += stats.next_seq[p].saturating_sub(stats.seen[p]);
+            lost += sent.saturating_sub(stats.seen[p]);
+        }
+        latencies.extend(stats.latencies);
+    }
+
+    println!("mode pubsub: {} publisher(s), {} subscriber(s), {} byte payloads, {:.1}s",
+             opts.publishers, opts.subscribers, opts.size, opts.duration.as_secs_f64());
+    println!("published: {} msgs ({:.1} msg/s)", total, total as f64 / elapsed);
+    println!("received:  {} msgs ({:.1} msg/s), expected {}",
+             received, received as f64 / elapsed, total * opts.subscribers as u64);
+    println!("lost: {} ({} in sequence gaps), out of order: {}", lost, gaps, reordered);
+    report_latency("end-to-end", latencies);
+    return Ok(());
+}
+
+async fn run_contender(client: Client, key: String, opts: Arc<Options>,
+                       until: Instant) -> Result<Vec<u64>> {
+    let mut latencies = Vec::new();
+    while Instant::now() < until {
+        let t = Instant::now();
+        client.lock_wait(&key, None, opts.lease, None).await?;
+        latencies.push(t.elapsed().as_micros() as u64);
+        if !opts.hold.is_zero() {
+            time::sleep(opts.hold).await;
+        }
+        client.unlock(&key, None).await?;
+    }
+    return Ok(latencies);
+}
+
+async fn bench_lock(opts: Arc<Options>) -> Result<()> {
+    let start = Instant::now();
+    let until = start + opts.duration;
+
+    let mut contenders = Vec::new();
+    for i in 0..opts.contenders {
+        let client = connect(&opts).await?;
+        let key = format!("bench_lock_{}", i % opts.keys);
+        contenders.push(tokio::spawn(run_contender(client, key, opts.clone(), until)));
+    }
+
+    let mut latencies = Vec::new();
+    for c in contenders {
+        if let Ok(l) = c.await {
+            latencies.extend(l?);
+        }
+    }
+    let elapsed = start.elapsed().as_secs_f64();
+
+    println!("mode lock: {} contender(s) on {} key(s), hold {}ms, {:.1}s",
+             opts.contenders, opts.keys, opts.hold.as_millis(), opts.duration.as_secs_f64());
+    println!("acquired: {} ({:.1} locks/s)", latencies.len(), latencies.len() as f64 / elapsed);
+    report_latency("acquire", latencies);
+    return Ok(());
+}
+
+fn main() {
+    let opts = Arc::new(parse_options());
+    let rt = tokio::runtime::Runtime::new().unwrap_or_else(|e| {
+        eprintln!("error: {}", e);
+        process::exit(1);
+    });
+
+    let result = match opts.mode.as_str() {
+        "lock" => rt.block_on(bench_lock(opts.clone())),
+        _ => rt.block_on(bench_pubsub(opts.clone()))
+    };
+
+    if let Err(e) = result {
+        eprintln!("error: {}", e);
+        process::exit(1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn payloads_round_trip() {
+        let payload = make_payload(3, 42, 1000, 64);
+        assert_eq!(payload.len(), 64);
+        assert_eq!(parse_payload(&payload), Some((3, 42, 1000)));
+        assert_eq!(parse_payload(&make_payload(0, 1, 2, 0)), Some((0, 1, 2)));
+        assert_eq!(parse_payload("x:1:2:"), None);
+    }
+}
@@ -0,0 +1,277 @@
+use std::env;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader as StdBufReader};
+use std::process;
+use std::time::Duration;
+
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::net::{TcpStream, UnixStream};
+
+use arcus_pubsub_client::{blocking, parse_push, Client, Error, LockReply, Message, Result};
+
+const USAGE: &str = "usage: arcus-pubsub-cli [-a <host:port>] [-s <unix socket path>] [command]
+
+commands:
+  sub <channel> [channel ...]              print messages as they arrive
+  pub <channel> <file|->                   publish every line of the file (or stdin)
+  lock <key> [sub_key] <lease> [--hold]    try the lock once, or with --hold wait for it
+                                           and keep renewing it until stdin is closed
+  unlock <key> [sub_key]
+  info <key>                               show holders and waiters of a lock
+  locks [pattern]                          list lock keys, optionally matching a glob
+  repl                                     send raw commands and pretty-print pushes (default)";
+
+enum Target {
+    Tcp(String),
+    Unix(String)
+}
+
+fn usage() -> ! {
+    eprintln!("{}", USAGE);
+    process::exit(1);
+}
+
+fn pretty(msg: &Message) -> String {
+    match msg {
+        Message::Channel { channel, payload } => return format!("[{}] {}", channel, payload),
+        Message::Unlocked { key, sub_key: Some(s) } => return format!("unlocked {} (sub_key={})", key, s),
+        Message::Unlocked { key, sub_key: None } => return format!("unlocked {}", key),
+        Message::Locked { key, token, sub_key: Some(s) } =>
+            return format!("locked {} (sub_key={}), fencing token {}", key, s, token),
+        Message::Locked { key, token, sub_key: None } => return format!("locked {}, fencing token {}", key, token),
+        Message::LeaseExpired { key, sub_key: Some(s) } => return format!("lease of {} (sub_key={}) expired", key, s),
+        Message::LeaseExpired { key, sub_key: None } => return format!("lease of {} expired", key),
+        Message::LockRevoked { key, sub_key: Some(s) } => return format!("{} (sub_key={}) was revoked", key, s),
+        Message::LockRevoked { key, sub_key: None } => return format!("{} was revoked", key),
+        Message::LatchOpen { key } => return format!("latch {} opened", key),
+        Message::BarrierOpen { key } => return format!("barrier {} opened", key),
+        Message::Leader { group, id, term } => return format!("{} is led by {} (term {})", group, id, term)
+    }
+}
+
+async fn connect(target: &Target) -> Result<Client> {
+    match target {
+        Target::Tcp(addr) => return Client::connect(addr.as_str()).await,
+        Target::Unix(path) => return Client::connect_unix(path).await
+    }
+}
+
+fn connect_blocking(target: &Target) -> Result<blocking::Client> {
+    match target {
+        Target::Tcp(addr) => return blocking::Client::connect(addr.as_str()),
+        Target::Unix(path) => return blocking::Client::connect_unix(path)
+    }
+}
+
+/* Parses `<key> [sub_key]` followed by `extra` more arguments. */
+fn key_args(args: &[String], extra: usize) -> (String, Option<i32>, &[String]) {
+    let (key, sub_key, rest) = match args.len() - extra {
+        1 => (&args[0], None, &args[1..]),
+        2 => (&args[0], Some(args[1].parse().unwrap_or_else(|_| usage())), &args[2..]),
+        _ => usage()
+    };
+    return (key.clone(), sub_key, rest);
+}
+
+async fn sub(target: &Target, channels: &[String]) -> Result<()> {
+    let client = connect(target).await?;
+    let channels: Vec<&str> = channels.iter().map(|s| s.as_str()).collect();
+    let mut sub = client.subscribe(&channels).await?;
+    while let Some(msg) = sub.recv().await {
+        if let Message::Channel { channel, payload } = msg {
+            println!("{} {}", channel, payload);
+        }
+    }
+    return Err(Error::Closed);
+}
+
+async fn publish(target: &Target, channel: &str, path: &str) -> Result<()> {
+    let reader: Box<dyn BufRead> = match path {
+        "-" => Box::new(StdBufReader::new(io::stdin())),
+        _ => Box::new(StdBufReader::new(File::open(path)?))
+    };
+
+    let client = connect(target).await?;
+    let (mut published, mut dropped) = (0, 0);
+    for line in reader.lines() {
+        let line = line?;
+        let payload = line.trim();
+        if payload.is_empty() {
+            continue;
+        }
+        if client.publish(channel, payload).await? {
+            published += 1;
+        } else {
+            dropped += 1;
+        }
+    }
+    println!("published {} message(s) to {}, {} without subscribers", published, channel, dropped);
+    return Ok(());
+}
+
+async fn lock_once(target: &Target, key: &str, sub_key: Option<i32>, lease: Duration) -> Result<()> {
+    let client = connect(target).await?;
+    match client.lock(key, sub_key, lease).await? {
+        LockReply::Ok(fence) => println!("OK {}", fence),
+        LockReply::Owned(fence) => println!("OWNED {}", fence),
+        LockReply::RetryLater(d) => println!("RETRY_LATER {:.3}", d.as_secs_f64())
+    }
+    return Ok(());
+}
+
+fn lock_hold(target: &Target, key: &str, sub_key: Option<i32>, lease: Duration) -> Result<()> {
+    let client = connect_blocking(target)?;
+    eprintln!("waiting for {}...", key);
+    let guard = client.lock(key, sub_key, lease)?;
+    eprintln!("holding {} (fencing token {}), close stdin or press enter to release",
+              key, guard.fencing_token());
+
+    let mut line = String::new();
+    let _ = io::stdin().read_line(&mut line);
+    if !guard.is_held() {
+        eprintln!("lease of {} was lost while holding it", key);
+    }
+    return guard.unlock();
+}
+
+async fn unlock(target: &Target, key: &str, sub_key: Option<i32>) -> Result<()> {
+    let client = connect(target).await?;
+    client.unlock(key, sub_key).await?;
+    println!("SUCCESS");
+    return Ok(());
+}
+
+async fn info(target: &Target, key: &str) -> Result<()> {
+    let client = connect(target).await?;
+    for line in client.lockinfo(key).await? {
+        println!("{}", line);
+    }
+    return Ok(());
+}
+
+async fn locks(target: &Target, pattern: Option<&str>) -> Result<()> {
+    let client = connect(target).await?;
+    for line in client.locks(pattern).await? {
+        println!("{}", line);
+    }
+    return Ok(());
+}
+
+async fn run_repl<S>(stream: S) -> Result<()>
+    where S: AsyncRead + AsyncWrite + Send + 'static
+{
+    let (reader, mut writer) = tokio::io::split(stream);
+    let printer = tokio::spawn(async move {
+        let mut lines = BufReader::new(reader).lines();
+        while let Ok(Some(line)) = lines.next_line().await {
+            match parse_push(&line) {
+                Some(msg) => println!("<< {}", pretty(&msg)),
+                None => println!("{}", line)
+            }
+        }
+    });
+
+    let mut stdin = BufReader::new(tokio::io::stdin()).lines();
+    while let Some(line) = stdin.next_line().await? {
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        writer.write_all(format!("{}\r\n", line).as_bytes()).await?;
+    }
+
+    /* Let the server answer what is still in flight, then close. */
+    writer.write_all(b"quit\r\n").await?;
+    let _ = printer.await;
+    return Ok(());
+}
+
+async fn repl(target: &Target) -> Result<()> {
+    match target {
+        Target::Tcp(addr) => return run_repl(TcpStream::connect(addr).await?).await,
+        Target::Unix(path) => return run_repl(UnixStream::connect(path).await?).await
+    }
+}
+
+fn main() {
+    let mut target = Target::Tcp("127.0.0.1:11211".to_string());
+    let mut args: Vec<String> = env::args().skip(1).collect();
+    while args.len() >= 2 && args[0].starts_with('-') {
+        match args[0].as_str() {
+            "-a" => target = Target::Tcp(args[1].clone()),
+            "-s" => target = Target::Unix(args[1].clone()),
+            _ => usage()
+        }
+        args.drain(..2);
+    }
+
+    let rt = tokio::runtime::Runtime::new().unwrap_or_else(|e| {
+        eprintln!("error: {}", e);
+        process::exit(1);
+    });
+
+    let cmd = args.first().cloned().unwrap_or_else(|| "repl".to_string());
+    let rest = if args.is_empty() { &[][..] } else { &args[1..] };
+    let result = match cmd.as_str() {
+        "sub" if !rest.is_empty() => rt.block_on(sub(&target, rest)),
+        "pub" if rest.len() == 2 => rt.block_on(publish(&target, &rest[0], &rest[1])),
+        "lock" => {
+            let hold = rest.iter().any(|a| a == "--hold");
+            let rest: Vec<String> = rest.iter().filter(|a| *a != "--hold").cloned().collect();
+            if rest.len() < 2 {
+                usage();
+            }
+            let (key, sub_key, rest) = key_args(&rest, 1);
+            /* try_from_secs_f64 turns away NaN, infinite and out of range leases. */
+            let lease = rest[0].parse::<f64>().ok()
+                                              .filter(|l| *l > 0.0)
+                                              .and_then(|l| Duration::try_from_secs_f64(l).ok())
+                                              .unwrap_or_else(|| usage());
+            if hold {
+                lock_hold(&target, &key, sub_key, lease)
+            } else {
+                rt.block_on(lock_once(&target, &key, sub_key, lease))
+            }
+        },
+        "unlock" if !rest.is_empty() => {
+            let (key, sub_key, _) = key_args(rest, 0);
+            rt.block_on(unlock(&target, &key, sub_key))
+        },
+        "info" if rest.len() == 1 => rt.block_on(info(&target, &rest[0])),
+        "locks" if rest.len() <= 1 => rt.block_on(locks(&target, rest.first().map(|s| s.as_str()))),
+        "repl" if rest.is_empty() => rt.block_on(repl(&target)),
+        _ => usage()
+    };
+
+    if let Err(e) = result {
+        eprintln!("error: {}", e);
+        process::exit(1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn strings(args: &[&str]) -> Vec<String> {
+        return args.iter().map(|s| s.to_string()).collect();
+    }
+
+    #[test]
+    fn key_args_take_an_optional_sub_key() {
+        let args = strings(&["k", "2.5"]);
+        let (key, sub_key, rest) = key_args(&args, 1);
+        assert_eq!((key.as_str(), sub_key, rest), ("k", None, &args[1..]));
+
+        let args = strings(&["k", "7", "2.5"]);
+        let (key, sub_key, rest) = key_args(&args, 1);
+        assert_eq!((key.as_str(), sub_key, rest), ("k", Some(7), &args[2..]));
+    }
+
+    #[test]
+    fn pushes_are_pretty_printed() {
+        assert_eq!(pretty(&Message::Channel { channel: "ch".to_string(), payload: "hi".to_string() }), "[ch] hi");
+        assert_eq!(pretty(&Message::Unlocked { key: "k".to_string(), sub_key: Some(3) }), "unlocked k (sub_key=3)");
+        assert_eq!(pretty(&Message::Unlocked { key: "k".to_string(), sub_key: None }), "unlocked k");
+    }
+}
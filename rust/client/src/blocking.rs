@@ -0,0 +1,204 @@
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::time::Duration;
+
+use tokio::net::ToSocketAddrs;
+use tokio::runtime::Runtime;
+use tokio::sync::Notify;
+use tokio::task::JoinHandle;
+
+use crate::{Error, Result};
+
+/* Leases are renewed after this fraction of the lease has passed. */
+pub const RENEW_FRACTION: f64 = 1.0 / 3.0;
+
+/*
+ * Blocking facade over the async client, driven by its own runtime.
+ * Not to be used from within an async context.
+ */
+#[derive(Clone)]
+pub struct Client {
+    rt: Arc<Runtime>,
+    inner: crate::Client
+}
+
+/*
+ * Holds a lock until dropped. The lease is renewed in the background with
+ * relock; once a renewal fails the lock is lost and is_held() turns false.
+ */
+pub struct LockGuard {
+    client: Client,
+    key: String,
+    sub_key: Option<i32>,
+    held: Arc<AtomicBool>,
+    fence: Arc<AtomicU64>,
+    stop: Arc<Notify>,
+    renewer: Option<JoinHandle<()>>
+}
+
+fn runtime() -> Result<Runtime> {
+    let rt = tokio::runtime::Builder::new_multi_thread()
+                                     .worker_threads(1)
+                                     .enable_all()
+                                     .build()?;
+    return Ok(rt);
+}
+
+impl Client {
+    pub fn connect<A: ToSocketAddrs>(addr: A) -> Result<Client> {
+        let rt = runtime()?;
+        let inner = rt.block_on(crate::Client::connect(addr))?;
+        return Ok(Client { rt: Arc::new(rt), inner });
+    }
+
+    pub fn connect_unix(path: &str) -> Result<Client> {
+        let rt = runtime()?;
+        let inner = rt.block_on(crate::Client::connect_unix(path))?;
+        return Ok(Client { rt: Arc::new(rt), inner });
+    }
+
+    pub fn publish(&self, channel: &str, payload: &str) -> Result<bool> {
+        return self.rt.block_on(self.inner.publish(channel, payload));
+    }
+
+    /* Waits as long as it takes to acquire the lock. */
+    pub fn lock(&self, key: &str, sub_key: Option<i32>, lease: Duration) -> Result<LockGuard> {
+        return self.acquire(key, sub_key, lease, None);
+    }
+
+    /* Gives up with Error::Timeout once `timeout` has passed without acquiring the lock. */
+    pub fn try_lock_for(&self, key: &str, sub_key: Option<i32>, lease: Duration,
+                        timeout: Duration) -> Result<LockGuard> {
+        return self.acquire(key, sub_key, lease, Some(timeout));
+    }
+
+    fn acquire(&self, key: &str, sub_key: Option<i32>, lease: Duration,
+               timeout: Option<Duration>) -> Result<LockGuard> {
+        let fence = self.rt.block_on(self.inner.lock_wait(key, sub_key, lease, timeout))?;
+
+        let held = Arc::new(AtomicBool::new(true));
+        let fence = Arc::new(AtomicU64::new(fence));
+        let stop = Arc::new(Notify::new());
+        let renewer = self.rt.spawn(renew(self.inner.clone(), key.to_string(), sub_key,
+                                          lease, held.clone(), fence.clone(), stop.clone()));
+        return Ok(LockGuard {
+            client: self.clone(),
+            key: key.to_string(),
+            sub_key,
+            held,
+            fence,
+            stop,
+            renewer: Some(renewer)
+        });
+    }
+}
+
+/* A request is never cancelled halfway, so replies stay matched to their commands. */
+async fn renew(client: crate::Client, key: String, sub_key: Option<i32>, lease: Duration,
+               held: Arc<AtomicBool>, fence: Arc<AtomicU64>, stop: Arc<Notify>) {
+    let period = lease.mul_f64(RENEW_FRACTION);
+    loop {
+        tokio::select! {
+            _ = tokio::time::sleep(period) => (),
+            _ = stop.notified() => return
+        }
+        match client.relock(&key, sub_key, lease).await {
+            Ok(f) => fence.store(f, Ordering::SeqCst),
+            Err(_) => {
+                held.store(false, Ordering::SeqCst);
+                return;
+            }
+        }
+    }
+}
+
+impl LockGuard {
+    pub fn key(&self) -> &str {
+        return &self.key;
+    }
+
+    /* Pass this along with writes so stale holders can be fenced off downstream. */
+    pub fn fencing_token(&self) -> u64 {
+        return self.fence.load(Ordering::SeqCst);
+    }
+
+    pub fn is_held(&self) -> bool {
+        return self.held.load(Ordering::SeqCst);
+    }
+
+    /* Same as dropping the guard, but reports the outcome of `unlock`. */
+    pub fn unlock(mut self) -> Result<()> {
+        return self.release();
+    }
+
+    fn release(&mut self) -> Result<()> {
+        let renewer = match self.renewer.take() {
+            Some(renewer) => renewer,
+            None => return Ok(())
+        };
+        self.stop.notify_one();
+        let _ = self.client.rt.block_on(renewer);
+
+        if !self.held.swap(false, Ordering::SeqCst) {
+            return Err(Error::NotOwned);
+        }
+        let fence = self.fence.load(Ordering::SeqCst);
+        return self.client.rt.block_on(self.client.inner.unlock_fenced(&self.key, self.sub_key, fence));
+    }
+}
+
+impl Drop for LockGuard {
+    fn drop(&mut self) {
+        let _ = self.release();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::{SocketAddr, TcpListener};
+    use std::thread;
+
+    /* Serves one connection, answering each request line with the next reply of `script`. */
+    fn fake_server(script: Vec<&'static str>) -> (SocketAddr, thread::JoinHandle<Vec<String>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut writer = stream.try_clone().unwrap();
+            let mut reader = BufReader::new(stream);
+            let mut requests: Vec<String> = Vec::new();
+            for reply in script {
+                let mut line = String::new();
+                if reader.read_line(&mut line).unwrap() == 0 {
+                    break;
+                }
+                requests.push(line.trim_end().to_string());
+                writer.write_all(reply.as_bytes()).unwrap();
+            }
+            return requests;
+        });
+        return (addr, server);
+    }
+
+    #[test]
+    fn guard_retries_on_unlocked_and_unlocks_on_drop() {
+        let (addr, server) = fake_server(vec!["RETRY_LATER 10.000\r\nUNLOCKED k\r\n", "OK 4\r\n", "SUCCESS\r\n"]);
+        let client = Client::connect(addr).unwrap();
+        let guard = client.lock("k", None, Duration::from_secs(5)).unwrap();
+        assert!(guard.is_held());
+        assert_eq!(guard.fencing_token(), 4);
+        drop(guard);
+
+        assert_eq!(server.join().unwrap(), vec!["lock k 5.000", "lock k 5.000", "unlock k fence=4"]);
+    }
+
+    #[test]
+    fn try_lock_for_gives_up() {
+        let (addr, _server) = fake_server(vec!["RETRY_LATER 10.000\r\n", "RETRY_LATER 9.900\r\n"]);
+        let client = Client::connect(addr).unwrap();
+        let result = client.try_lock_for("k", Some(1), Duration::from_secs(5), Duration::from_millis(100));
+        assert!(matches!(result, Err(Error::Timeout)));
+    }
+}
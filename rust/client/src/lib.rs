@@ -0,0 +1,754 @@
+pub mod blocking;
+mod protocol;
+
+use std::collections::{HashMap, VecDeque};
+use std::ops::RangeInclusive;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+
+use futures_core::Stream;
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::net::{TcpStream, ToSocketAddrs, UnixStream};
+use tokio::sync::{mpsc, oneshot};
+
+pub use protocol::{ElectReply, Error, FlightReply, LockReply, Message, MultiLockReply, RateLimitReply, Result, parse_push};
+use protocol::*;
+
+struct Pending {
+    multi: bool,
+    tx: oneshot::Sender<Vec<String>>
+}
+
+#[derive(Default)]
+struct Shared {
+    closed: bool,
+    pending: VecDeque<Pending>,
+    channels: HashMap<String, Vec<mpsc::UnboundedSender<Message>>>,
+    unlocked: Vec<mpsc::UnboundedSender<Message>>
+}
+
+type Writer = Box<dyn AsyncWrite + Send + Unpin>;
+
+/* Largest data block the server reads, as in the registry. */
+const MAX_DATA_LENGTH: usize = 1024 * 1024;
+
+/*
+ * One connection to an arcus-pubsub server. Commands are pipelined and their
+ * replies matched in order; CHANNEL and UNLOCKED pushes are routed to the
+ * streams returned by subscribe() and unlocked().
+ */
+#[derive(Clone)]
+pub struct Client {
+    writer: Arc<tokio::sync::Mutex<Writer>>,
+    shared: Arc<Mutex<Shared>>
+}
+
+pub struct Subscription {
+    channels: Vec<String>,
+    rx: mpsc::UnboundedReceiver<Message>
+}
+
+impl Subscription {
+    pub fn channels(&self) -> &[String] {
+        return &self.channels;
+    }
+
+    pub async fn recv(&mut self) -> Option<Message> {
+        return self.rx.recv().await;
+    }
+
+    /* Same as recv(), so `sub.next()` works without importing a StreamExt. */
+    pub async fn next(&mut self) -> Option<Message> {
+        return self.rx.recv().await;
+    }
+}
+
+impl Stream for Subscription {
+    type Item = Message;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Message>> {
+        return self.rx.poll_recv(cx);
+    }
+}
+
+impl Client {
+    pub async fn connect<A: ToSocketAddrs>(addr: A) -> Result<Client> {
+        let stream = TcpStream::connect(addr).await?;
+        stream.set_nodelay(true)?;
+        return Ok(Client::new(stream));
+    }
+
+    pub async fn connect_unix(path: &str) -> Result<Client> {
+        let stream = UnixStream::connect(path).await?;
+        return Ok(Client::new(stream));
+    }
+
+    /* Must be called within a tokio runtime: the reply reader runs as a spawned task. */
+    pub fn new<S>(stream: S) -> Client
+        where S: AsyncRead + AsyncWrite + Send + 'static
+    {
+        let (reader, writer) = tokio::io::split(stream);
+        let shared = Arc::new(Mutex::new(Shared::default()));
+        tokio::spawn(read_loop(reader, shared.clone()));
+        return Client {
+            writer: Arc::new(tokio::sync::Mutex::new(Box::new(writer))),
+            shared
+        };
+    }
+
+    async fn request(&self, cmd: String, multi: bool) -> Result<Vec<String>> {
+        let (tx, rx) = oneshot::channel();
+        {
+            /* Queue the reply slot under the writer lock so slots stay in wire order. */
+            let mut writer = self.writer.lock().await;
+            {
+                let mut shared = self.shared.lock().unwrap();
+                if shared.closed {
+                    return Err(Error::Closed);
+                }
+                shared.pending.push_back(Pending { multi, tx });
+            }
+            writer.write_all(cmd.as_bytes()).await?;
+            writer.flush().await?;
+        }
+        return rx.await.map_err(|_| Error::Closed);
+    }
+
+    async fn request_line(&self, cmd: String) -> Result<String> {
+        let mut lines = self.request(cmd, false).await?;
+        return lines.pop().ok_or(Error::Closed);
+    }
+
+    /* Returns false when nobody is subscribed to the channel. */
+    pub async fn publish(&self, channel: &str, payload: &str) -> Result<bool> {
+        check_token("channel", channel)?;
+        check_token("payload", payload)?;
+        let line = self.request_line(format!("publish {} {}\r\n", channel, payload)).await?;
+        match line.as_str() {
+            "PUBLISHED" => return Ok(true),
+            "NOT_FOUND" => return Ok(false),
+            _ => return Err(reply_error(&line))
+        }
+    }
+
+    pub async fn subscribe(&self, channels: &[&str]) -> Result<Subscription> {
+        if channels.is_empty() {
+            return Err(Error::InvalidArgument("no channel given".to_string()));
+        }
+        for channel in channels {
+            check_token("channel", channel)?;
+        }
+
+        /* Register before sending so no message published right after the reply is lost. */
+        let (tx, rx) = mpsc::unbounded_channel();
+        {
+            let mut shared = self.shared.lock().unwrap();
+            for channel in channels {
+                shared.channels.entry(channel.to_string())
+                               .or_default()
+                               .push(tx.clone());
+            }
+        }
+
+        let lines = self.request(format!("subscribe {}\r\n", channels.join(" ")), true).await?;
+        let mut result = Ok(());
+        if lines.first().is_none_or(|l| !l.starts_with("SUBSCRIBE ")) {
+            result = Err(reply_error(lines.first().map_or("", |l| l.as_str())));
+        }
+        for line in lines.iter().skip(1) {
+            if line.ends_with(" TYPE_MISMATCH") {
+                result = Err(Error::TypeMismatch);
+            }
+        }
+
+        if let Err(e) = result {
+            let mut shared = self.shared.lock().unwrap();
+            for channel in channels {
+                if let Some(v) = shared.channels.get_mut(*channel) {
+                    v.retain(|s| !s.same_channel(&tx));
+                }
+            }
+            return Err(e);
+        }
+
+        return Ok(Subscription {
+            channels: channels.iter().map(|s| s.to_string()).collect(),
+            rx
+        });
+    }
+
+    /* Drops every local stream on the channel as well as the server side subscription. */
+    pub async fn unsubscribe(&self, channel: &str) -> Result<()> {
+        check_token("channel", channel)?;
+        self.shared.lock().unwrap().channels.remove(channel);
+        let line = self.request_line(format!("unsubscribe {}\r\n", channel)).await?;
+        match line.as_str() {
+            "SUCCESS" | "NOT_SUBSCRIBED" | "NOT_FOUND_CHANNEL" => return Ok(()),
+            _ => return Err(reply_error(&line))
+        }
+    }
+
+    /*
+     * Stream of lock notifications: UNLOCKED and LOCKED for locks this connection
+     * waited on, LEASE_EXPIRED and LOCK_REVOKED for holds it lost.
+     */
+    pub fn unlocked(&self) -> Subscription {
+        let (tx, rx) = mpsc::unbounded_channel();
+        self.shared.lock().unwrap().unlocked.push(tx);
+        return Subscription { channels: Vec::new(), rx };
+    }
+
+    pub async fn lock(&self, key: &str, sub_key: Option<i32>, lease: Duration) -> Result<LockReply> {
+        check_token("key", key)?;
+        let line = self.request_line(lock_command("lock", key, sub_key, lease, None)).await?;
+        return parse_lock_reply(&line);
+    }
+
+    /* Like lock(), but taking a lock already owned adds a hold that needs its own unlock. */
+    pub async fn lock_reentrant(&self, key: &str, sub_key: Option<i32>, lease: Duration) -> Result<LockReply> {
+        check_token("key", key)?;
+        let line = self.request_line(lock_command("lock", key, sub_key, lease, Some("reentrant"))).await?;
+        return parse_lock_reply(&line);
+    }
+
+    /*
+     * Like lock(), but the lock belongs to `owner`, an opaque token of the caller's
+     * choosing, instead of this connection. It survives a reconnect until its lease
+     * runs out: calling this again with the same token from a new connection
+     * answers OWNED and renews it.
+     */
+    pub async fn lock_owned(&self, key: &str, sub_key: Option<i32>, lease: Duration,
+                            owner: &str) -> Result<LockReply> {
+        check_token("key", key)?;
+        check_token("owner", owner)?;
+        let owner = format!("owner={}", owner);
+        let line = self.request_line(lock_command("lock", key, sub_key, lease, Some(&owner))).await?;
+        return parse_lock_reply(&line);
+    }
+
+    /*
+     * Lets the server hold the request until the lock is handed over, instead of
+     * retrying on the client. The server reads nothing more from this connection
+     * until it answers, so later commands on this client wait behind it; renew
+     * other locks from another client meanwhile.
+     */
+    pub async fn lock_parked(&self, key: &str, sub_key: Option<i32>, lease: Duration,
+                             timeout: Duration) -> Result<u64> {
+        check_token("key", key)?;
+        let wait = format!("wait {:.3}", timeout.as_secs_f64());
+        let line = self.request_line(lock_command("lock", key, sub_key, lease, Some(&wait))).await?;
+        if line == "TIMEOUT" {
+            return Err(Error::Timeout);
+        }
+        match parse_lock_reply(&line)? {
+            LockReply::Ok(fence) | LockReply::Owned(fence) => return Ok(fence),
+            LockReply::RetryLater(_) => return Err(Error::Server(line))
+        }
+    }
+
+    /*
+     * Like lock(), but when turned away the connection is queued and the lock is
+     * handed to it in arrival order, announced by a LOCKED push.
+     */
+    pub async fn lock_fair(&self, key: &str, sub_key: Option<i32>, lease: Duration) -> Result<LockReply> {
+        check_token("key", key)?;
+        let line = self.request_line(lock_command("lock", key, sub_key, lease, Some("fair"))).await?;
+        return parse_lock_reply(&line);
+    }
+
+    /*
+     * Waits in the fair queue until the lock is handed over. Asking again after
+     * the holder's lease has run out moves the queue along without a release.
+     * On timeout the place in the queue is given up.
+     */
+    pub async fn lock_fair_wait(&self, key: &str, sub_key: Option<i32>, lease: Duration,
+                                timeout: Option<Duration>) -> Result<u64> {
+        let deadline = timeout.map(|t| Instant::now() + t);
+        let mut notices = self.unlocked();
+        loop {
+            match self.lock_fair(key, sub_key, lease).await? {
+                LockReply::Ok(fence) | LockReply::Owned(fence) => return Ok(fence),
+                LockReply::RetryLater(remaining) => {
+                    let mut wait = remaining.max(Duration::from_millis(1));
+                    if let Some(deadline) = deadline {
+                        let left = deadline.saturating_duration_since(Instant::now());
+                        if left.is_zero() {
+                            /* Leaves the queue, or releases the lock if it was handed over meanwhile. */
+                            let _ = self.unlock(key, sub_key).await;
+                            return Err(Error::Timeout);
+                        }
+                        wait = wait.min(left);
+                    }
+                    if let Ok(Some(token)) = tokio::time::timeout(wait, wait_locked(&mut notices, key, sub_key)).await {
+                        return Ok(token);
+                    }
+                }
+            }
+        }
+    }
+
+    /*
+     * Shared hold of a read-write lock. Taking it while holding the write lock
+     * downgrades that hold; unlock() releases either kind.
+     */
+    pub async fn rlock(&self, key: &str, sub_key: Option<i32>, lease: Duration) -> Result<LockReply> {
+        check_token("key", key)?;
+        let line = self.request_line(lock_command("rlock", key, sub_key, lease, None)).await?;
+        return parse_lock_reply(&line);
+    }
+
+    /* Exclusive hold of a read-write lock, upgrading a read hold if it is the only one. */
+    pub async fn wlock(&self, key: &str, sub_key: Option<i32>, lease: Duration) -> Result<LockReply> {
+        check_token("key", key)?;
+        let line = self.request_line(lock_command("wlock", key, sub_key, lease, None)).await?;
+        return parse_lock_reply(&line);
+    }
+
+    /*
+     * Takes `permits` of a semaphore of `max` permits. Calling it again renews
+     * the lease and resizes the hold; RETRY_LATER means not enough are free.
+     */
+    pub async fn sem_acquire(&self, key: &str, permits: u32, max: u32, lease: Duration) -> Result<LockReply> {
+        check_token("key", key)?;
+        let cmd = format!("sem_acquire {} {} {} {:.3}\r\n", key, permits, max, lease.as_secs_f64());
+        let line = self.request_line(cmd).await?;
+        return parse_lock_reply(&line);
+    }
+
+    /* Gives back `permits` of the hold, or all of it with None. */
+    pub async fn sem_release(&self, key: &str, permits: Option<u32>) -> Result<()> {
+        check_token("key", key)?;
+        let cmd = match permits {
+            Some(p) => format!("sem_release {} {}\r\n", key, p),
+            None => format!("sem_release {}\r\n", key)
+        };
+        let line = self.request_line(cmd).await?;
+        return parse_unlock_reply(&line);
+    }
+
+    /*
+     * Retries after RETRY_LATER as soon as UNLOCKED arrives or the holder's lease
+     * runs out. Gives up with Error::Timeout once `timeout` has passed.
+     * Returns the fencing token of the acquisition.
+     */
+    pub async fn lock_wait(&self, key: &str, sub_key: Option<i32>, lease: Duration,
+                           timeout: Option<Duration>) -> Result<u64> {
+        let deadline = timeout.map(|t| Instant::now() + t);
+        let mut unlocked = self.unlocked();
+        loop {
+            match self.lock(key, sub_key, lease).await? {
+                LockReply::Ok(fence) | LockReply::Owned(fence) => return Ok(fence),
+                LockReply::RetryLater(remaining) => {
+                    let mut wait = remaining.max(Duration::from_millis(1));
+                    if let Some(deadline) = deadline {
+                        let left = deadline.saturating_duration_since(Instant::now());
+                        if left.is_zero() {
+                            return Err(Error::Timeout);
+                        }
+                        wait = wait.min(left);
+                    }
+                    let _ = tokio::time::timeout(wait, wait_unlocked(&mut unlocked, key)).await;
+                }
+            }
+        }
+    }
+
+    /*
+     * Extends the lease of a hold this connection still has, returning its fencing
+     * token. Fails with NotOwned or NotFound once the lease has run out.
+     */
+    pub async fn relock(&self, key: &str, sub_key: Option<i32>, lease: Duration) -> Result<u64> {
+        check_token("key", key)?;
+        let line = self.request_line(lock_command("relock", key, sub_key, lease, None)).await?;
+        match parse_lock_reply(&line)? {
+            LockReply::Owned(fence) => return Ok(fence),
+            _ => return Err(Error::Server(line))
+        }
+    }
+
+    /* Time left until the current holders' leases run out, or None if nobody holds the key. */
+    pub async fn lockttl(&self, key: &str) -> Result<Option<Duration>> {
+        check_token("key", key)?;
+        let line = self.request_line(format!("lockttl {}\r\n", key)).await?;
+        if line == "NOT_FOUND" {
+            return Ok(None);
+        }
+        match line.strip_prefix("TTL ").map(|s| s.parse::<f64>()) {
+            Some(Ok(secs)) => return Ok(Some(Duration::from_secs_f64(secs.max(0.0)))),
+            _ => return Err(reply_error(&line))
+        }
+    }
+
+    /*
+     * Raw lockinfo reply without END: the LOCKINFO header followed by OWNER,
+     * HOLDER and WAITER lines. Fails with NotFound for an unknown key.
+     */
+    pub async fn lockinfo(&self, key: &str) -> Result<Vec<String>> {
+        check_token("key", key)?;
+        let mut lines = self.request(format!("lockinfo {}\r\n", key), true).await?;
+        if lines.first().is_none_or(|l| !l.starts_with("LOCKINFO ")) {
+            return Err(reply_error(lines.first().map_or("", |l| l.as_str())));
+        }
+        lines.pop();
+        return Ok(lines);
+    }
+
+    /* One `<key> <type> <holders> <waiters> <ttl>` line per lock key matching the glob `pattern`. */
+    pub async fn locks(&self, pattern: Option<&str>) -> Result<Vec<String>> {
+        let cmd = match pattern {
+            Some(p) => {
+                check_token("pattern", p)?;
+                format!("locks {}\r\n", p)
+            },
+            None => "locks\r\n".to_string()
+        };
+        let mut lines = self.request(cmd, true).await?;
+        if lines.first().is_none_or(|l| !l.starts_with("LOCKS ")) {
+            return Err(reply_error(lines.first().map_or("", |l| l.as_str())));
+        }
+        lines.pop();
+        lines.remove(0);
+        return Ok(lines);
+    }
+
+    /* Takes all of `keys` or none of them. */
+    pub async fn mlock(&self, keys: &[&str], lease: Duration) -> Result<MultiLockReply> {
+        if keys.is_empty() {
+            return Err(Error::InvalidArgument("no key given".to_string()));
+        }
+        for key in keys {
+            check_token("key", key)?;
+        }
+        let cmd = format!("mlock {:.3} {}\r\n", lease.as_secs_f64(), keys.join(" "));
+        let line = self.request_line(cmd).await?;
+        return parse_mlock_reply(&line, keys.len());
+    }
+
+    /* Fails with NotOwned if any of `keys` was not released. */
+    pub async fn munlock(&self, keys: &[&str]) -> Result<()> {
+        if keys.is_empty() {
+            return Err(Error::InvalidArgument("no key given".to_string()));
+        }
+        for key in keys {
+            check_token("key", key)?;
+        }
+        let line = self.request_line(format!("munlock {}\r\n", keys.join(" "))).await?;
+        return parse_unlock_reply(&line);
+    }
+
+    /* Admin: takes every hold of the key away. Fails with NotFound if nobody holds it. */
+    pub async fn unlock_force(&self, key: &str) -> Result<()> {
+        check_token("key", key)?;
+        let line = self.request_line(format!("unlock_force {}\r\n", key)).await?;
+        return parse_unlock_reply(&line);
+    }
+
+    /*
+     * Admin: hands the lock to connection `conn`, as shown by lockinfo.
+     * Returns the new fencing token; a lock taken with `owner=` is refused
+     * with `TOKEN_BOUND`.
+     */
+    pub async fn lock_transfer(&self, key: &str, conn: u64) -> Result<u64> {
+        check_token("key", key)?;
+        let line = self.request_line(format!("lock_transfer {} {}\r\n", key, conn)).await?;
+        match parse_lock_reply(&line)? {
+            LockReply::Ok(fence) => return Ok(fence),
+            _ => return Err(Error::Server(line))
+        }
+    }
+
+    /* Returns false if a latch that has not opened yet exists under `key`. */
+    pub async fn latch_create(&self, key: &str, count: u32) -> Result<bool> {
+        check_token("key", key)?;
+        let line = self.request_line(format!("latch_create {} {}\r\n", key, count)).await?;
+        match line.as_str() {
+            "CREATED" => return Ok(true),
+            "EXISTS" => return Ok(false),
+            _ => return Err(reply_error(&line))
+        }
+    }
+
+    /* Counts the latch down by `n`, returning how many countdowns are left. */
+    pub async fn latch_countdown(&self, key: &str, n: u32) -> Result<u32> {
+        check_token("key", key)?;
+        let line = self.request_line(format!("latch_countdown {} {}\r\n", key, n)).await?;
+        match line.strip_prefix("COUNT ").map(|s| s.parse::<u32>()) {
+            Some(Ok(count)) => return Ok(count),
+            _ => return Err(reply_error(&line))
+        }
+    }
+
+    /* Waits until the latch opens, or fails with Error::Timeout. */
+    pub async fn latch_wait(&self, key: &str, timeout: Option<Duration>) -> Result<()> {
+        check_token("key", key)?;
+        let mut notices = self.unlocked();
+        let line = self.request_line(format!("latch_wait {}\r\n", key)).await?;
+        if line == "OPEN" {
+            return Ok(());
+        }
+        if !line.starts_with("WAITING ") {
+            return Err(reply_error(&line));
+        }
+        return wait_for(timeout, wait_open(&mut notices, Message::LatchOpen { key: key.to_string() })).await;
+    }
+
+    /*
+     * Waits until `parties` connections have arrived at the barrier, or fails with
+     * Error::Timeout; this connection still counts as arrived after a timeout.
+     */
+    pub async fn barrier_wait(&self, key: &str, parties: u32, timeout: Option<Duration>) -> Result<()> {
+        check_token("key", key)?;
+        let mut notices = self.unlocked();
+        let line = self.request_line(format!("barrier_wait {} {}\r\n", key, parties)).await?;
+        if line == "OPEN" {
+            return Ok(());
+        }
+        if !line.starts_with("WAITING ") {
+            return Err(reply_error(&line));
+        }
+        return wait_for(timeout, wait_open(&mut notices, Message::BarrierOpen { key: key.to_string() })).await;
+    }
+
+    /* Reserves the next `count` ids of the sequence `name`. */
+    pub async fn seq_next(&self, name: &str, count: u64) -> Result<RangeInclusive<u64>> {
+        check_token("name", name)?;
+        let line = self.request_line(format!("seq_next {} {}\r\n", name, count)).await?;
+        if let Some((first, last)) = line.strip_prefix("RANGE ").and_then(|s| s.split_once(' ')) &&
+            let (Ok(first), Ok(last)) = (first.parse::<u64>(), last.parse::<u64>()) {
+            return Ok(first..=last);
+        }
+        return Err(reply_error(&line));
+    }
+
+    /*
+     * Blocks until another client notifies `key`, returning the payload it sent
+     * (empty without one), or fails with Error::Timeout. The server reads nothing
+     * more from this connection until it answers, so later commands on this
+     * client wait behind it.
+     */
+    pub async fn wait(&self, key: &str, timeout: Duration) -> Result<String> {
+        check_token("key", key)?;
+        let line = self.request_line(format!("wait {} {:.3}\r\n", key, timeout.as_secs_f64())).await?;
+        if line == "TIMEOUT" {
+            return Err(Error::Timeout);
+        }
+        if line == "NOTIFIED" {
+            return Ok(String::new());
+        }
+        match line.strip_prefix("NOTIFIED ") {
+            Some(payload) => return Ok(payload.to_string()),
+            None => return Err(reply_error(&line))
+        }
+    }
+
+    /*
+     * Joins the flight `key`: the first caller leads and must call flight_done
+     * within `lease`, later callers wait for the result it hands over, at most
+     * `lease` before failing with Error::Timeout. Like `wait`, a follower holds
+     * up later commands on this client until then.
+     */
+    pub async fn flight(&self, key: &str, lease: Duration) -> Result<FlightReply> {
+        check_token("key", key)?;
+        let mut reply = self.request(format!("flight {} {:.3}\r\n", key, lease.as_secs_f64()), false).await?;
+        let line = reply.first().cloned().ok_or(Error::Closed)?;
+        if line == "LEADER" {
+            return Ok(FlightReply::Leader);
+        }
+        if line == "TIMEOUT" {
+            return Err(Error::Timeout);
+        }
+        if line.starts_with("RESULT ") && reply.len() == 2 {
+            return Ok(FlightReply::Result(reply.pop().unwrap()));
+        }
+        return Err(reply_error(&line));
+    }
+
+    /*
+     * Hands the result of the flight `key` to its followers; returns how many got it.
+     * The server takes up to 1 MiB of it; a larger one is refused before it is sent.
+     */
+    pub async fn flight_done(&self, key: &str, data: &str) -> Result<u64> {
+        check_token("key", key)?;
+        if data.len() > MAX_DATA_LENGTH {
+            return Err(Error::InvalidArgument("data must be at most 1 MiB".to_string()));
+        }
+        let line = self.request_line(format!("flight_done {} {}\r\n{}\r\n", key, data.len(), data)).await?;
+        match line.strip_prefix("WOKEN ").map(|s| s.parse::<u64>()) {
+            Some(Ok(n)) => return Ok(n),
+            _ => return Err(reply_error(&line))
+        }
+    }
+
+    /* Wakes `n` of the clients waiting on `key`, or all of them with None; returns how many woke. */
+    pub async fn notify(&self, key: &str, n: Option<u32>, payload: Option<&str>) -> Result<u64> {
+        check_token("key", key)?;
+        let mut cmd = match n {
+            Some(n) => format!("notify {} {}", key, n),
+            None => format!("notify {} all", key)
+        };
+        if let Some(p) = payload {
+            check_token("payload", p)?;
+            cmd += &format!(" {}", p);
+        }
+        let line = self.request_line(cmd + "\r\n").await?;
+        match line.strip_prefix("WOKEN ").map(|s| s.parse::<u64>()) {
+            Some(Ok(n)) => return Ok(n),
+            _ => return Err(reply_error(&line))
+        }
+    }
+
+    /*
+     * Takes `cost` tokens from the bucket `key` of `capacity` tokens refilled at
+     * `refill_per_sec`, shared by every client using the same key.
+     */
+    pub async fn ratelimit(&self, key: &str, capacity: u32, refill_per_sec: f64,
+                           cost: u32) -> Result<RateLimitReply> {
+        check_token("key", key)?;
+        let cmd = format!("ratelimit {} {} {} {}\r\n", key, capacity, refill_per_sec, cost);
+        let line = self.request_line(cmd).await?;
+        return parse_ratelimit_reply(&line);
+    }
+
+    /*
+     * Stands for election in `group` as `id`. The leader keeps leading by calling
+     * this again within `lease`; every change of leader is pushed as Message::Leader.
+     */
+    pub async fn elect(&self, group: &str, id: &str, lease: Duration) -> Result<ElectReply> {
+        check_token("group", group)?;
+        check_token("id", id)?;
+        let cmd = format!("elect {} {} {:.3}\r\n", group, id, lease.as_secs_f64());
+        let line = self.request_line(cmd).await?;
+        return parse_elect_reply(&line);
+    }
+
+    pub async fn unlock(&self, key: &str, sub_key: Option<i32>) -> Result<()> {
+        check_token("key", key)?;
+        let line = self.request_line(unlock_command(key, sub_key, None)).await?;
+        return parse_unlock_reply(&line);
+    }
+
+    /* Only releases the acquisition identified by `fence`, failing with NotOwned otherwise. */
+    pub async fn unlock_fenced(&self, key: &str, sub_key: Option<i32>, fence: u64) -> Result<()> {
+        check_token("key", key)?;
+        let fence = format!("fence={}", fence);
+        let line = self.request_line(unlock_command(key, sub_key, Some(&fence))).await?;
+        return parse_unlock_reply(&line);
+    }
+
+    /* Releases a lock taken with lock_owned(), from this or any other connection. */
+    pub async fn unlock_owned(&self, key: &str, sub_key: Option<i32>, owner: &str) -> Result<()> {
+        check_token("key", key)?;
+        check_token("owner", owner)?;
+        let owner = format!("owner={}", owner);
+        let line = self.request_line(unlock_command(key, sub_key, Some(&owner))).await?;
+        return parse_unlock_reply(&line);
+    }
+}
+
+async fn wait_unlocked(unlocked: &mut Subscription, key: &str) {
+    while let Some(msg) = unlocked.recv().await {
+        if let Message::Unlocked { key: k, .. } = msg && k == key {
+            return;
+        }
+    }
+}
+
+async fn wait_open(notices: &mut Subscription, open: Message) -> Result<()> {
+    while let Some(msg) = notices.recv().await {
+        if msg == open {
+            return Ok(());
+        }
+    }
+    return Err(Error::Closed);
+}
+
+async fn wait_locked(notices: &mut Subscription, key: &str, sub_key: Option<i32>) -> Option<u64> {
+    while let Some(msg) = notices.recv().await {
+        if let Message::Locked { key: k, token, sub_key: s } = msg && k == key && s == sub_key {
+            return Some(token);
+        }
+    }
+    return None;
+}
+
+async fn wait_for<F: Future<Output = Result<()>>>(timeout: Option<Duration>, fut: F) -> Result<()> {
+    match timeout {
+        Some(t) => return tokio::time::timeout(t, fut).await.unwrap_or(Err(Error::Timeout)),
+        None => return fut.await
+    }
+}
+
+fn dispatch_push(shared: &mut Shared, msg: Message) {
+    match &msg {
+        Message::Channel { channel, .. } => {
+            if let Some(v) = shared.channels.get_mut(channel) {
+                v.retain(|tx| tx.send(msg.clone()).is_ok());
+            }
+        },
+        Message::Unlocked { .. } | Message::Locked { .. } |
+        Message::LeaseExpired { .. } | Message::LockRevoked { .. } |
+        Message::LatchOpen { .. } | Message::BarrierOpen { .. } | Message::Leader { .. } => {
+            shared.unlocked.retain(|tx| tx.send(msg.clone()).is_ok());
+        }
+    }
+}
+
+fn is_multi_header(line: &str) -> bool {
+    return ["SUBSCRIBE ", "LOCKINFO ", "LOCKS "].iter().any(|h| line.starts_with(h));
+}
+
+async fn read_loop<R: AsyncRead + Unpin>(reader: R, shared: Arc<Mutex<Shared>>) {
+    let mut lines = BufReader::new(reader).lines();
+    let mut collecting: Option<(Pending, Vec<String>)> = None;
+
+    while let Ok(Some(line)) = lines.next_line().await {
+        /* `RESULT <bytes>` is followed by a data block, read before taking the lock. */
+        let mut data = None;
+        if let Some(Ok(n)) = line.strip_prefix("RESULT ").map(|s| s.parse::<usize>()) {
+            let mut buf = vec![0u8; n + 2];
+            if lines.get_mut().read_exact(&mut buf).await.is_err() {
+                break;
+            }
+            buf.truncate(n);
+            data = Some(String::from_utf8_lossy(&buf).into_owned());
+        }
+
+        let mut shared = shared.lock().unwrap();
+
+        /* A multi-line reply is written in one piece, so every line up to END is ours. */
+        if let Some((pending, mut reply)) = collecting.take() {
+            let done = line == "END";
+            reply.push(line);
+            if done {
+                let _ = pending.tx.send(reply);
+            } else {
+                collecting = Some((pending, reply));
+            }
+            continue;
+        }
+
+        if let Some(msg) = parse_push(&line) {
+            dispatch_push(&mut shared, msg);
+            continue;
+        }
+
+        /* A reply nobody waits for is dropped. */
+        if let Some(pending) = shared.pending.pop_front() {
+            if let Some(data) = data {
+                let _ = pending.tx.send(vec![line, data]);
+            } else if pending.multi && is_multi_header(&line) {
+                collecting = Some((pending, vec![line]));
+            } else {
+                let _ = pending.tx.send(vec![line]);
+            }
+        }
+    }
+
+    let mut shared = shared.lock().unwrap();
+    shared.closed = true;
+    shared.pending.clear();
+    shared.channels.clear();
+    shared.unlocked.clear();
+}
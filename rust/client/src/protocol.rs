@@ -0,0 +1,333 @@
+use std::fmt;
+use std::io;
+use std::time::Duration;
+
+/* Frames the server pushes without being asked. */
+#[derive(Debug, Clone, PartialEq)]
+pub enum Message {
+    Channel { channel: String, payload: String },
+    Unlocked { key: String, sub_key: Option<i32> },
+    /* A fair lock handed over to this connection, with its fencing token. */
+    Locked { key: String, token: u64, sub_key: Option<i32> },
+    /* A hold of this connection was released because its lease ran out. */
+    LeaseExpired { key: String, sub_key: Option<i32> },
+    /* An admin took a hold of this connection away. */
+    LockRevoked { key: String, sub_key: Option<i32> },
+    LatchOpen { key: String },
+    /* The last party arrived at a barrier this connection waits at. */
+    BarrierOpen { key: String },
+    /* A new leader of an election this connection is a candidate of. */
+    Leader { group: String, id: String, term: u64 }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum LockReply {
+    /* Carries the fencing token of the acquisition. */
+    Ok(u64),
+    Owned(u64),
+    RetryLater(Duration)
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum RateLimitReply {
+    /* Whole tokens left in the bucket. */
+    Allowed(u64),
+    /* How long until the bucket holds enough tokens. */
+    Limited(Duration)
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum FlightReply {
+    /* Compute the result and hand it over with flight_done. */
+    Leader,
+    /* The result the leader handed over. */
+    Result(String)
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ElectReply {
+    Leader(u64),
+    Follower { leader: String, term: u64 }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum MultiLockReply {
+    /* Fencing tokens, in the order the keys were given. */
+    Ok(Vec<u64>),
+    /* Keys held by someone else; UNLOCKED follows for each of them. */
+    Contended(Vec<String>)
+}
+
+#[derive(Debug)]
+pub enum Error {
+    Io(io::Error),
+    Closed,
+    TypeMismatch,
+    NotFound,
+    NotOwned,
+    MaxMismatch,
+    NotAllowed,
+    /* Waiting would have closed a circular wait over these keys. */
+    Deadlock(Vec<String>),
+    /* The leader of the flight went away before handing over a result. */
+    FlightFailed,
+    Timeout,
+    InvalidArgument(String),
+    Server(String)
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Io(e) => write!(f, "io error: {}", e),
+            Error::Closed => write!(f, "connection closed"),
+            Error::TypeMismatch => write!(f, "key is used by another type"),
+            Error::NotFound => write!(f, "key not found"),
+            Error::NotOwned => write!(f, "lock is not owned by this connection"),
+            Error::MaxMismatch => write!(f, "key exists with another number of permits or parties"),
+            Error::NotAllowed => write!(f, "admin command not allowed from this address"),
+            Error::Deadlock(keys) => write!(f, "deadlock over {}", keys.join(" ")),
+            Error::FlightFailed => write!(f, "flight leader went away"),
+            Error::Timeout => write!(f, "timed out"),
+            Error::InvalidArgument(s) => write!(f, "invalid argument: {}", s),
+            Error::Server(s) => write!(f, "unexpected reply: {}", s)
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Error {
+        return Error::Io(e);
+    }
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+/* Returns the push frame carried by `line`, or None if it is part of a command reply. */
+pub fn parse_push(line: &str) -> Option<Message> {
+    let mut tokens = line.splitn(3, ' ');
+    match tokens.next() {
+        Some("CHANNEL") => {
+            let channel = tokens.next()?;
+            let payload = tokens.next()?;
+            return Some(Message::Channel {
+                channel: channel.to_string(),
+                payload: payload.to_string()
+            });
+        },
+        Some("UNLOCKED") => {
+            let key = tokens.next()?;
+            let sub_key = parse_sub_key(tokens.next())?;
+            return Some(Message::Unlocked { key: key.to_string(), sub_key });
+        },
+        Some("LEASE_EXPIRED") => {
+            let key = tokens.next()?;
+            let sub_key = parse_sub_key(tokens.next())?;
+            return Some(Message::LeaseExpired { key: key.to_string(), sub_key });
+        },
+        Some("LOCK_REVOKED") => {
+            let key = tokens.next()?;
+            let sub_key = parse_sub_key(tokens.next())?;
+            return Some(Message::LockRevoked { key: key.to_string(), sub_key });
+        },
+        Some("LATCH_OPEN") => return Some(Message::LatchOpen { key: tokens.next()?.to_string() }),
+        Some("BARRIER_OPEN") => return Some(Message::BarrierOpen { key: tokens.next()?.to_string() }),
+        Some("LEADER") => {
+            let group = tokens.next()?;
+            let (id, term) = tokens.next()?.split_once(' ')?;
+            return Some(Message::Leader {
+                group: group.to_string(),
+                id: id.to_string(),
+                term: term.parse().ok()?
+            });
+        },
+        Some("LOCKED") => {
+            let key = tokens.next()?;
+            let mut rest = tokens.next()?.splitn(2, ' ');
+            let token = rest.next()?.parse().ok()?;
+            let sub_key = parse_sub_key(rest.next())?;
+            return Some(Message::Locked { key: key.to_string(), token, sub_key });
+        },
+        _ => return None
+    }
+}
+
+/* The optional ` [sub_key=N]` suffix of a push; None if it is malformed. */
+fn parse_sub_key(s: Option<&str>) -> Option<Option<i32>> {
+    match s {
+        Some(s) => return Some(Some(s.strip_prefix("[sub_key=")?
+                                     .strip_suffix(']')?
+                                     .parse().ok()?)),
+        None => return Some(None)
+    }
+}
+
+pub fn parse_lock_reply(line: &str) -> Result<LockReply> {
+    if let Some(Ok(fence)) = line.strip_prefix("OK ").map(|s| s.parse::<u64>()) {
+        return Ok(LockReply::Ok(fence));
+    }
+    if let Some(Ok(fence)) = line.strip_prefix("OWNED ").map(|s| s.parse::<u64>()) {
+        return Ok(LockReply::Owned(fence));
+    }
+    if let Some(Ok(secs)) = line.strip_prefix("RETRY_LATER ").map(|s| s.parse::<f64>()) {
+        return Ok(LockReply::RetryLater(Duration::from_secs_f64(secs.max(0.0))));
+    }
+    return Err(reply_error(line));
+}
+
+/* `keys` is how many keys were asked for: a fence must come back for each. */
+pub fn parse_mlock_reply(line: &str, keys: usize) -> Result<MultiLockReply> {
+    if let Some(rest) = line.strip_prefix("CONTENDED ") {
+        return Ok(MultiLockReply::Contended(rest.split(' ').map(|k| k.to_string()).collect()));
+    }
+    if let Some(rest) = line.strip_prefix("OK ") && rest.split(' ').count() == keys &&
+        let Ok(fences) = rest.split(' ').map(|f| f.parse::<u64>()).collect() {
+        return Ok(MultiLockReply::Ok(fences));
+    }
+    return Err(reply_error(line));
+}
+
+pub fn parse_ratelimit_reply(line: &str) -> Result<RateLimitReply> {
+    if let Some(Ok(remaining)) = line.strip_prefix("ALLOWED ").map(|s| s.parse::<u64>()) {
+        return Ok(RateLimitReply::Allowed(remaining));
+    }
+    if let Some(Ok(secs)) = line.strip_prefix("LIMITED ").map(|s| s.parse::<f64>()) {
+        return Ok(RateLimitReply::Limited(Duration::from_secs_f64(secs.max(0.0))));
+    }
+    return Err(reply_error(line));
+}
+
+pub fn parse_elect_reply(line: &str) -> Result<ElectReply> {
+    if let Some(Ok(term)) = line.strip_prefix("ELECTED ").map(|s| s.parse::<u64>()) {
+        return Ok(ElectReply::Leader(term));
+    }
+    if let Some((leader, term)) = line.strip_prefix("FOLLOWER ").and_then(|s| s.split_once(' ')) &&
+        let Ok(term) = term.parse::<u64>() {
+        return Ok(ElectReply::Follower { leader: leader.to_string(), term });
+    }
+    return Err(reply_error(line));
+}
+
+/* A reentrant lock answers `HELD <count>` until its last hold is released. */
+pub fn parse_unlock_reply(line: &str) -> Result<()> {
+    if line == "SUCCESS" || line.starts_with("HELD ") {
+        return Ok(());
+    }
+    return Err(reply_error(line));
+}
+
+pub fn reply_error(line: &str) -> Error {
+    match line {
+        "TYPE_MISMATCH" => return Error::TypeMismatch,
+        "NOT_FOUND" => return Error::NotFound,
+        "NOT_OWNED" => return Error::NotOwned,
+        l if l.starts_with("NOT_OWNED ") => return Error::NotOwned,
+        "MAX_MISMATCH" => return Error::MaxMismatch,
+        "NOT_ALLOWED" => return Error::NotAllowed,
+        "FLIGHT_FAILED" => return Error::FlightFailed,
+        l if l.starts_with("DEADLOCK ") =>
+            return Error::Deadlock(l.split(' ').skip(1).map(|k| k.to_string()).collect()),
+        _ => return Error::Server(line.to_string())
+    }
+}
+
+/* Keys and payloads travel as single ascii tokens. */
+pub fn check_token(what: &str, s: &str) -> Result<()> {
+    if s.is_empty() || s.contains([' ', '\r', '\n']) {
+        return Err(Error::InvalidArgument(format!("{} must be a non-empty token without spaces", what)));
+    }
+    return Ok(());
+}
+
+/* `verb` is one of lock, rlock and wlock. */
+pub fn lock_command(verb: &str, key: &str, sub_key: Option<i32>, lease: Duration, flag: Option<&str>) -> String {
+    let mut cmd = format!("{} {}", verb, key);
+    if let Some(s) = sub_key {
+        cmd += &format!(" {}", s);
+    }
+    cmd += &format!(" {:.3}", lease.as_secs_f64());
+    if let Some(f) = flag {
+        cmd += &format!(" {}", f);
+    }
+    return cmd + "\r\n";
+}
+
+/* `option` is e.g. `fence=12` or `owner=<token>`. */
+pub fn unlock_command(key: &str, sub_key: Option<i32>, option: Option<&str>) -> String {
+    let mut cmd = format!("unlock {}", key);
+    if let Some(s) = sub_key {
+        cmd += &format!(" {}", s);
+    }
+    if let Some(o) = option {
+        cmd += &format!(" {}", o);
+    }
+    return cmd + "\r\n";
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pushes_are_told_from_replies() {
+        assert_eq!(parse_push("CHANNEL ch hello world"),
+                   Some(Message::Channel { channel: "ch".to_string(), payload: "hello world".to_string() }));
+        assert_eq!(parse_push("UNLOCKED k"), Some(Message::Unlocked { key: "k".to_string(), sub_key: None }));
+        assert_eq!(parse_push("UNLOCKED k [sub_key=-3]"),
+                   Some(Message::Unlocked { key: "k".to_string(), sub_key: Some(-3) }));
+        assert_eq!(parse_push("UNLOCKED k [sub_key=x]"), None);
+        assert_eq!(parse_push("LOCKED k 12 [sub_key=1]"),
+                   Some(Message::Locked { key: "k".to_string(), token: 12, sub_key: Some(1) }));
+        assert_eq!(parse_push("LOCKED k"), None);
+        assert_eq!(parse_push("LEASE_EXPIRED k"), Some(Message::LeaseExpired { key: "k".to_string(), sub_key: None }));
+        assert_eq!(parse_push("LOCK_REVOKED k [sub_key=2]"),
+                   Some(Message::LockRevoked { key: "k".to_string(), sub_key: Some(2) }));
+        assert_eq!(parse_push("LATCH_OPEN k"), Some(Message::LatchOpen { key: "k".to_string() }));
+        assert_eq!(parse_push("BARRIER_OPEN k"), Some(Message::BarrierOpen { key: "k".to_string() }));
+        assert_eq!(parse_push("CHANNEL ch"), None);
+        assert_eq!(parse_push("PUBLISHED"), None);
+    }
+
+    #[test]
+    fn lock_replies() {
+        assert_eq!(parse_lock_reply("OK 7").unwrap(), LockReply::Ok(7));
+        assert_eq!(parse_lock_reply("OWNED 7").unwrap(), LockReply::Owned(7));
+        assert!(matches!(parse_lock_reply("OK"), Err(Error::Server(_))));
+        assert_eq!(parse_lock_reply("RETRY_LATER 1.250").unwrap(), LockReply::RetryLater(Duration::from_millis(1250)));
+        assert_eq!(parse_lock_reply("RETRY_LATER -0.001").unwrap(), LockReply::RetryLater(Duration::ZERO));
+        assert!(matches!(parse_lock_reply("TYPE_MISMATCH"), Err(Error::TypeMismatch)));
+        assert!(matches!(parse_lock_reply("RETRY_LATER soon"), Err(Error::Server(_))));
+        assert!(matches!(parse_lock_reply("DEADLOCK a b"), Err(Error::Deadlock(keys)) if keys == ["a", "b"]));
+    }
+
+    #[test]
+    fn unlock_replies() {
+        assert!(parse_unlock_reply("SUCCESS").is_ok());
+        assert!(matches!(parse_unlock_reply("NOT_OWNED"), Err(Error::NotOwned)));
+        assert!(matches!(parse_unlock_reply("NOT_FOUND"), Err(Error::NotFound)));
+        assert!(matches!(parse_unlock_reply("SERVER_ERROR out of memory"), Err(Error::Server(_))));
+    }
+
+    #[test]
+    fn commands_are_single_lines() {
+        assert!(check_token("key", "a b").is_err());
+        assert!(check_token("key", "").is_err());
+        assert!(check_token("key", "k").is_ok());
+        assert_eq!(lock_command("lock", "k", Some(2), Duration::from_millis(1500), None), "lock k 2 1.500\r\n");
+        assert_eq!(lock_command("wlock", "k", None, Duration::from_secs(3), Some("fair")), "wlock k 3.000 fair\r\n");
+        assert_eq!(unlock_command("k", None, None), "unlock k\r\n");
+        assert_eq!(unlock_command("k", Some(2), Some("fence=9")), "unlock k 2 fence=9\r\n");
+    }
+
+    #[test]
+    fn mlock_replies() {
+        assert_eq!(parse_mlock_reply("OK 3 4", 2).unwrap(), MultiLockReply::Ok(vec![3, 4]));
+        assert_eq!(parse_mlock_reply("CONTENDED a b", 3).unwrap(),
+                   MultiLockReply::Contended(vec!["a".to_string(), "b".to_string()]));
+        assert!(matches!(parse_mlock_reply("OK 3 x", 2), Err(Error::Server(_))));
+        assert!(matches!(parse_mlock_reply("OK 3 4", 3), Err(Error::Server(_))));
+        assert!(matches!(parse_unlock_reply("NOT_OWNED a"), Err(Error::NotOwned)));
+    }
+}
@@ -0,0 +1,3524 @@
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap, HashSet, VecDeque};
+use std::collections::hash_map::Entry;
+use std::fs;
+use std::path::PathBuf;
+use std::sync::{Condvar, LazyLock, Mutex, Once, OnceLock};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::thread;
+use std::time::{Instant, Duration, SystemTime, UNIX_EPOCH};
+
+#[derive(PartialEq)]
+#[allow(dead_code)]
+pub enum IEKType {
+    PubSub = 0,
+    Lock = 1,
+    RWLock = 2,
+    Semaphore = 3,
+    Latch = 4,
+    Barrier = 5,
+    Election = 6,
+    RateLimit = 7,
+    Condition = 8,
+    Sequence = 9,
+    Flight = 10
+}
+
+#[derive(PartialEq, Clone, Copy)]
+pub enum CMDType {
+    Publish,
+    Subscribe,
+    Unsubscribe,
+    Lock,
+    Unlock,
+    RLock,
+    WLock,
+    SemAcquire,
+    SemRelease,
+    Relock,
+    LockTTL,
+    LockInfo,
+    Locks,
+    MLock,
+    MUnlock,
+    UnlockForce,
+    LockTransfer,
+    LatchCreate,
+    LatchCountdown,
+    LatchWait,
+    BarrierWait,
+    Elect,
+    RateLimit,
+    Wait,
+    Notify,
+    SeqNext,
+    Flight,
+    FlightDone,
+    Unknown
+}
+
+/* What happens to a held Lock when its connection goes away. */
+#[derive(PartialEq, Clone, Copy)]
+pub enum DisconnectPolicy {
+    Release,
+    /* Kept until the lease runs out. */
+    Keep,
+    /* Kept at most this long, for the owner token to reclaim it. */
+    Grace(Duration)
+}
+
+/* Connections to push to, each with the sub_keys it asked for. */
+pub type Waiters = HashMap<usize, HashSet<Option<i32>>>;
+
+pub struct IEKData {
+    pub state: IEKState,
+    /*
+     * Subscribers of a channel, or whom to tell when the key moves along: lock
+     * waiters turned away with RETRY_LATER or CONTENDED, latch waiters and the
+     * parties arrived at a barrier.
+     */
+    pub waiters: Waiters
+}
+
+/* What kind of key it is, with the state only that kind of key has. */
+pub enum IEKState {
+    PubSub,
+    Lock(LockState),
+    RWLock(RWLockState),
+    Semaphore(SemaphoreState),
+    Latch(LatchState),
+    /* The number of parties. */
+    Barrier(u32),
+    Election(ElectionState),
+    RateLimit(Bucket),
+    /* Connections parked by `wait`, in arrival order. */
+    Condition(VecDeque<Parked>),
+    Sequence(Sequence),
+    Flight(FlightState)
+}
+
+/* The exclusive hold of a Lock. */
+pub struct Hold {
+    /*
+     * Where pushes go: the owning connection, or the one that last presented
+     * the token of a token-bound lock, None while no connection presents it.
+     */
+    pub conn: Option<usize>,
+    /* Set when the lock was taken with `owner=<token>`: the token owns it. */
+    pub token: Option<String>,
+    /* Set by `disconnect=` on the lock; the global policy applies otherwise. */
+    pub on_disconnect: Option<DisconnectPolicy>,
+    pub sub_key: Option<i32>,
+    pub lease_time: Instant,
+    pub fence: u64,
+    /* When the current owner took the lock, for lockinfo. */
+    pub acquired: SystemTime,
+    pub hold_count: u32
+}
+
+#[derive(Default)]
+pub struct LockState {
+    pub hold: Option<Hold>,
+    /* Waiters asking for a fair hand-off or parked with `wait`, in arrival order. */
+    pub queue: VecDeque<Waiter>,
+    /* Callers turned away with RETRY_LATER or CONTENDED, until when they count as waiting. */
+    pub retrying: HashMap<(usize, Option<i32>), Instant>
+}
+
+#[derive(Default)]
+pub struct RWLockState {
+    pub writer: Option<((usize, Option<i32>), Holder)>,
+    pub readers: HashMap<(usize, Option<i32>), Holder>,
+    /* Writers turned away while readers held the lock, until when they block new readers. */
+    pub writer_waiters: HashMap<(usize, Option<i32>), Instant>,
+    /* Readers turned away with RETRY_LATER, until when they count as waiting. */
+    pub retrying: HashMap<(usize, Option<i32>), Instant>
+}
+
+pub struct LatchState {
+    /* Countdowns left; the latch is open at zero. */
+    pub left: u32,
+    /* Set once open: until when latecomers are still answered OPEN. */
+    pub open_until: Option<Instant>
+}
+
+pub struct SemaphoreState {
+    pub max_permits: u32,
+    /* Holds by connection, with the permits each one takes. */
+    pub holders: HashMap<usize, (Holder, u32)>
+}
+
+pub struct ElectionState {
+    pub leader: Option<Leader>,
+    /* Candidates to take over from the leader, in arrival order. */
+    pub followers: VecDeque<Candidate>
+}
+
+pub struct Leader {
+    pub conn: usize,
+    pub id: String,
+    pub lease_time: Instant,
+    pub term: u64,
+    pub acquired: SystemTime
+}
+
+pub struct Candidate {
+    pub conn: usize,
+    pub id: String,
+    /* The lease asked for, started once the candidate leads. */
+    pub lease: Duration
+}
+
+pub struct FlightState {
+    pub leader: usize,
+    pub lease_time: Instant,
+    pub fence: u64,
+    pub acquired: SystemTime,
+    /* Connections parked until the leader hands over the result, in arrival order. */
+    pub followers: VecDeque<Parked>
+}
+
+/* Token bucket of a RateLimit. */
+pub struct Bucket {
+    pub capacity: f64,
+    pub refill_per_sec: f64,
+    pub tokens: f64,
+    pub updated: Instant
+}
+
+impl Bucket {
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.refill_per_sec).min(self.capacity);
+        self.updated = now;
+    }
+
+    /* When the bucket is full again, and so no different from no bucket at all. */
+    fn full_at(&self) -> Instant {
+        let secs = (self.capacity - self.tokens) / self.refill_per_sec;
+        return self.updated + Duration::from_millis((secs * 1000.0) as u64);
+    }
+}
+
+pub struct Sequence {
+    pub next: u64,
+    /* Ids below it may have been handed out before a restart; persisted ahead of next. */
+    pub ceiling: u64,
+    /* Bumped from SEQ_GEN when the ceiling is raised; ids are handed out once it is saved. */
+    pub generation: u64
+}
+
+/* A hold of an RWLock, either side, or of a Semaphore. */
+pub struct Holder {
+    pub lease_time: Instant,
+    pub fence: u64,
+    pub acquired: SystemTime
+}
+
+pub struct Waiter {
+    pub conn: usize,
+    pub sub_key: Option<i32>,
+    /* The lease asked for, started when the lock is handed over. */
+    pub lease: Duration,
+    /* Set for a parked `lock ... wait`, which is answered on hand-off or at this deadline. */
+    pub deadline: Option<Instant>,
+    pub token: Option<String>,
+    pub on_disconnect: Option<DisconnectPolicy>
+}
+
+/* A connection parked until it is answered or its deadline passes. */
+pub struct Parked {
+    pub conn: usize,
+    pub deadline: Instant
+}
+
+impl IEKData {
+    fn new(state: IEKState) -> IEKData {
+        return IEKData { state, waiters: HashMap::new() };
+    }
+}
+
+impl IEKState {
+    pub fn iek_type(&self) -> IEKType {
+        match self {
+            IEKState::PubSub => return IEKType::PubSub,
+            IEKState::Lock(_) => return IEKType::Lock,
+            IEKState::RWLock(_) => return IEKType::RWLock,
+            IEKState::Semaphore(_) => return IEKType::Semaphore,
+            IEKState::Latch(_) => return IEKType::Latch,
+            IEKState::Barrier(_) => return IEKType::Barrier,
+            IEKState::Election(_) => return IEKType::Election,
+            IEKState::RateLimit(_) => return IEKType::RateLimit,
+            IEKState::Condition(_) => return IEKType::Condition,
+            IEKState::Sequence(_) => return IEKType::Sequence,
+            IEKState::Flight(_) => return IEKType::Flight
+        }
+    }
+
+    /* Lease ends of the holds on the key whose lease still runs. */
+    fn live_leases(&self, now: Instant) -> Vec<Instant> {
+        let leases: Vec<Instant> = match self {
+            IEKState::Lock(l) => l.hold.iter().map(|h| h.lease_time).collect(),
+            IEKState::RWLock(rw) => rw.leases().collect(),
+            IEKState::Semaphore(s) => s.holders.values().map(|(h, _)| h.lease_time).collect(),
+            IEKState::Election(e) => e.leader.iter().map(|l| l.lease_time).collect(),
+            IEKState::Flight(f) => vec![f.lease_time],
+            _ => Vec::new()
+        };
+        return leases.into_iter().filter(|t| *t >= now).collect();
+    }
+
+    /* Whether nobody holds the key, as far as leases still run. */
+    fn is_free(&self, now: Instant) -> bool {
+        return self.live_leases(now).is_empty();
+    }
+
+    /* Connections queued or parked on the key. */
+    fn queued(&self) -> usize {
+        match self {
+            IEKState::Lock(l) => return l.queue.len(),
+            IEKState::Election(e) => return e.followers.len(),
+            IEKState::Condition(queue) => return queue.len(),
+            IEKState::Flight(f) => return f.followers.len(),
+            _ => return 0
+        }
+    }
+}
+
+impl LockState {
+    /* The exclusive hold, if its lease still runs. */
+    fn owned(&self, now: Instant) -> Option<&Hold> {
+        return self.hold.as_ref().filter(|h| h.lease_time >= now);
+    }
+}
+
+impl Hold {
+    /*
+     * Without a policy of its own or a global one, a token-bound lock is kept
+     * for its token to reclaim and any other lock is released.
+     */
+    fn disconnect_policy(&self) -> DisconnectPolicy {
+        if let Some(policy) = self.on_disconnect.or(DISCONNECT.get().copied()) {
+            return policy;
+        }
+        if self.token.is_some() {
+            return DisconnectPolicy::Keep;
+        }
+        return DisconnectPolicy::Release;
+    }
+
+    /* A token-bound hold belongs to whoever presents the token, any other to its connection. */
+    fn owned_by(&self, conn: usize, sub_key: Option<i32>, token: Option<&str>) -> bool {
+        if self.sub_key != sub_key {
+            return false;
+        }
+        match &self.token {
+            Some(t) => return token == Some(t.as_str()),
+            None => return token.is_none() && self.conn == Some(conn)
+        }
+    }
+}
+
+impl RWLockState {
+    /* Lease ends of the writer and the readers, run out or not. */
+    fn leases(&self) -> impl Iterator<Item = Instant> + '_ {
+        return self.writer.iter()
+                          .map(|(_, h)| h.lease_time)
+                          .chain(self.readers.values().map(|h| h.lease_time));
+    }
+
+    fn write_held(&self, now: Instant) -> bool {
+        return self.writer.as_ref().is_some_and(|(_, h)| h.lease_time >= now);
+    }
+
+    fn is_free(&self, now: Instant) -> bool {
+        return self.leases().all(|t| t < now);
+    }
+
+    fn purge_expired(&mut self, now: Instant) {
+        self.readers.retain(|_, h| h.lease_time >= now);
+        self.writer_waiters.retain(|_, until| *until >= now);
+        self.retrying.retain(|_, until| *until >= now);
+    }
+}
+
+/*
+ * Delivers asynchronous messages (CHANNEL, UNLOCKED, ...) to connections.
+ * Connections are identified by the same usize the commands were run with:
+ * the cookie pointer inside memcached, a plain counter in the standalone server.
+ */
+pub trait Transport: Send + Sync {
+    fn send(&self, msgs: Vec<(usize, String)>);
+
+    /*
+     * Late reply to a command process_command answered with an empty string.
+     * A host that stops reading the connection meanwhile resumes it here.
+     */
+    fn reply(&self, conn: usize, msg: String) {
+        self.send(vec![(conn, msg)]);
+    }
+
+    /* Peer address of a connection, for lockinfo and the admin ACL. */
+    fn client_ip(&self, _conn: usize) -> Option<String> {
+        return None;
+    }
+
+    /* Admin commands, allowed or not, are reported here. */
+    fn log(&self, _msg: &str) {
+    }
+}
+
+static TRANSPORT: OnceLock<Box<dyn Transport>> = OnceLock::new();
+
+static DISCONNECT: OnceLock<DisconnectPolicy> = OnceLock::new();
+
+/* Where sequences keep their ceilings, if they are to survive a restart. */
+static SEQ_FILE: OnceLock<PathBuf> = OnceLock::new();
+
+/* How far ahead of the ids handed out a persisted ceiling is written, to write it rarely. */
+const SEQ_BLOCK: u64 = 1000;
+
+/*
+ * Ceilings are raised under IEK but written outside it: SEQ_GEN counts the
+ * raises, SEQ_SAVED is the last one on disk, and SEQ_SAVE lets one writer at
+ * a time cover every raise made so far.
+ */
+static SEQ_GEN: AtomicU64 = AtomicU64::new(0);
+
+static SEQ_SAVED: AtomicU64 = AtomicU64::new(0);
+
+static SEQ_SAVE: Mutex<()> = Mutex::new(());
+
+/* Client addresses allowed to run admin commands; nobody until the host sets them. */
+static ADMINS: OnceLock<HashSet<String>> = OnceLock::new();
+
+static IEK: LazyLock<Mutex<HashMap<String, IEKData>>> = LazyLock::new(|| {
+    Mutex::new(HashMap::new())
+});
+
+/* Largest data block a command may carry, like the default item size limit of memcached. */
+pub const MAX_DATA_LENGTH: usize = 1024 * 1024;
+
+/* How long a writer turned away keeps new readers out beyond the current holders' leases. */
+const WRITER_WAIT_GRACE: Duration = Duration::from_secs(1);
+
+/* How long an open latch still answers OPEN to latecomers before it is dropped. */
+const LATCH_OPEN_TTL: Duration = Duration::from_secs(60);
+
+/*
+ * How long a caller turned away with RETRY_LATER still counts as waiting for
+ * deadlock detection after the leases it was told about ran out. One that gave
+ * up and never came back stops counting then.
+ */
+const RETRY_GRACE: Duration = Duration::from_secs(1);
+
+/*
+ * Fencing tokens come from one counter shared by all keys, so they keep
+ * increasing per key even after an unlocked key is dropped from IEK.
+ */
+static FENCE: AtomicU64 = AtomicU64::new(0);
+
+/*
+ * The last term of each election group. Kept apart from IEK, which drops a
+ * group nobody runs for, so a group's terms keep increasing when it comes back.
+ */
+static TERMS: LazyLock<Mutex<HashMap<String, u64>>> = LazyLock::new(|| {
+    Mutex::new(HashMap::new())
+});
+
+/*
+ * Keys to look at again at a given time, served by one background thread.
+ * `due` keeps the earliest pending time of each key, so a key is in the heap
+ * once however often its leases are renewed; on_timer schedules what is next.
+ */
+struct Timers {
+    heap: BinaryHeap<Reverse<(Instant, String)>>,
+    due: HashMap<String, Instant>
+}
+
+static TIMERS: LazyLock<(Mutex<Timers>, Condvar)> = LazyLock::new(|| {
+    (Mutex::new(Timers { heap: BinaryHeap::new(), due: HashMap::new() }), Condvar::new())
+});
+
+static TIMER_THREAD: Once = Once::new();
+
+static CONN: LazyLock<Mutex<HashMap<usize, HashSet<String>>>> = LazyLock::new(|| {
+    Mutex::new(HashMap::new())
+});
+
+fn next_fence() -> u64 {
+    return FENCE.fetch_add(1, Ordering::Relaxed) + 1;
+}
+
+pub fn set_transport(transport: Box<dyn Transport>) -> bool {
+    return TRANSPORT.set(transport).is_ok();
+}
+
+pub fn set_admins(ips: Vec<String>) -> bool {
+    return ADMINS.set(ips.into_iter().collect()).is_ok();
+}
+
+/*
+ * Persists sequences in `path`, one `<name> <ceiling>` line each, and resumes
+ * the ones already in it from their ceilings. Fails if the file exists but
+ * cannot be read.
+ */
+pub fn set_sequence_file(path: &str) -> bool {
+    let content = match fs::read_to_string(path) {
+        Ok(content) => content,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => String::new(),
+        Err(_e) => return false
+    };
+    if SEQ_FILE.set(PathBuf::from(path)).is_err() {
+        return false;
+    }
+
+    let mut iek = IEK.lock().unwrap();
+    for line in content.lines() {
+        if let Some((name, ceiling)) = line.split_once(' ') &&
+            let Ok(ceiling) = ceiling.parse::<u64>() {
+            iek.insert(name.to_string(),
+                       IEKData::new(IEKState::Sequence(Sequence { next: ceiling, ceiling, generation: 0 })));
+        }
+    }
+    return true;
+}
+
+/*
+ * Makes sure the ceilings raised up to `generation` are on disk, rewriting the file
+ * through a temporary one, so a crash never leaves it half written. The file
+ * is written without holding IEK; a caller finding a newer save already done
+ * returns at once.
+ */
+fn save_sequences(generation: u64) -> bool {
+    let path = match SEQ_FILE.get() {
+        Some(path) => path,
+        None => return true
+    };
+    if SEQ_SAVED.load(Ordering::Acquire) >= generation {
+        return true;
+    }
+    let _save = SEQ_SAVE.lock().unwrap();
+    if SEQ_SAVED.load(Ordering::Acquire) >= generation {
+        return true;
+    }
+
+    let saving: u64;
+    let mut content = String::new();
+    {
+        let iek = IEK.lock().unwrap();
+        saving = SEQ_GEN.load(Ordering::Relaxed);
+        for (name, data) in iek.iter() {
+            if let IEKState::Sequence(seq) = &data.state {
+                content += &format!("{} {}\n", name, seq.ceiling);
+            }
+        }
+    }
+    let tmp = path.with_extension("tmp");
+    if fs::write(&tmp, content).is_err() || fs::rename(&tmp, path).is_err() {
+        return false;
+    }
+    SEQ_SAVED.store(saving, Ordering::Release);
+    return true;
+}
+
+/* `release`, `keep` or a grace period in seconds. */
+fn parse_disconnect_policy(s: &str) -> Option<DisconnectPolicy> {
+    match s {
+        "release" => return Some(DisconnectPolicy::Release),
+        "keep" => return Some(DisconnectPolicy::Keep),
+        _ => match s.parse::<f64>() {
+            Ok(secs) if secs >= 0.0 => return Some(DisconnectPolicy::Grace(Duration::from_millis((secs * 1000.0) as u64))),
+            _ => return None
+        }
+    }
+}
+
+/* Global disconnect policy for locks that do not set their own. */
+pub fn set_disconnect_policy(policy: &str) -> bool {
+    return match parse_disconnect_policy(policy) {
+        Some(policy) => DISCONNECT.set(policy).is_ok(),
+        None => false
+    };
+}
+
+pub fn unsubscribe_all(conn: usize) {
+    let s: HashSet<String>;
+    {
+        let mut conns = CONN.lock().unwrap();
+        s = conns.remove(&conn).unwrap_or_default();
+    }
+
+    {
+        let mut iek = IEK.lock().unwrap();
+        for iekey in s {
+            if let Some(iekdata) = iek.get_mut(&iekey) && detach_conn(&iekey, iekdata, conn) {
+                iek.remove(&iekey);
+            }
+        }
+    }
+}
+
+/*
+ * Takes away what a closed connection had on the key, moving the key along as
+ * a release would. Returns true once the key can be dropped.
+ */
+fn detach_conn(iekey: &str, data: &mut IEKData, conn: usize) -> bool {
+    data.waiters.remove(&conn);
+    let cur_time = Instant::now();
+    let IEKData { state, waiters } = data;
+    match state {
+        IEKState::PubSub => return waiters.is_empty(),
+        /* An open latch stays for latecomers until its timer; a barrier nobody waits at is forgotten. */
+        IEKState::Latch(_) => return false,
+        IEKState::Barrier(_) => return waiters.is_empty(),
+        IEKState::RateLimit(_) | IEKState::Sequence(_) => return false,
+        IEKState::Flight(f) => {
+            f.followers.retain(|w| w.conn != conn);
+            if f.leader == conn {
+                fail_flight(f);
+                return true;
+            }
+            return false;
+        },
+        IEKState::Condition(queue) => {
+            queue.retain(|w| w.conn != conn);
+            return queue.is_empty();
+        },
+        IEKState::Election(e) => {
+            e.followers.retain(|c| c.conn != conn);
+            if e.leader.as_ref().is_some_and(|l| l.conn == conn) {
+                e.leader = None;
+                return !elect_next(iekey, e, None);
+            }
+            return false;
+        },
+        IEKState::Lock(l) => {
+            l.queue.retain(|w| w.conn != conn);
+            l.retrying.retain(|(c, _), _| *c != conn);
+            let held = l.hold.as_ref().is_some_and(|h| h.conn == Some(conn));
+            if let Some(h) = l.hold.as_mut() && held && h.lease_time >= cur_time &&
+                h.disconnect_policy() != DisconnectPolicy::Release {
+                /*
+                 * Kept for whoever presents the token until the lease or the grace
+                 * period runs out. A lock without one gets the empty token, which
+                 * no client can present, so it stays held all the same.
+                 */
+                let policy = h.disconnect_policy();
+                h.conn = None;
+                h.token.get_or_insert_with(String::new);
+                if let DisconnectPolicy::Grace(grace) = policy && cur_time + grace < h.lease_time {
+                    h.lease_time = cur_time + grace;
+                    schedule(h.lease_time, iekey);
+                }
+            } else if held || l.hold.as_ref().is_none_or(|h| h.conn.is_none() && h.lease_time < cur_time) {
+                l.hold = None;
+                if hand_off(iekey, l, waiters) {
+                    return false;
+                }
+                if waiters.is_empty() {
+                    return true;
+                }
+                do_publish(waiters, format!("UNLOCKED {}", iekey));
+            }
+            return false;
+        },
+        IEKState::RWLock(rw) => {
+            rw.readers.retain(|(c, _), _| *c != conn);
+            rw.writer_waiters.retain(|(c, _), _| *c != conn);
+            rw.retrying.retain(|(c, _), _| *c != conn);
+            if rw.writer.as_ref().is_some_and(|((c, _), _)| *c == conn) {
+                rw.writer = None;
+            }
+            if rw.writer.is_some() {
+                return false;
+            }
+            if waiters.is_empty() && rw.readers.is_empty() {
+                return true;
+            }
+            if rw.is_free(cur_time) {
+                do_publish(waiters, format!("UNLOCKED {}", iekey));
+            }
+            return false;
+        },
+        IEKState::Semaphore(s) => {
+            /* Every permit given back to a semaphore may let a waiter in. */
+            let permits_freed = s.holders.remove(&conn).is_some();
+            if waiters.is_empty() && s.holders.is_empty() {
+                return true;
+            }
+            if permits_freed || s.holders.values().all(|(h, _)| h.lease_time < cur_time) {
+                do_publish(waiters, format!("UNLOCKED {}", iekey));
+            }
+            return false;
+        }
+    }
+}
+
+fn do_publish(waiters: &Waiters, msg: String) {
+    let mut msgs: Vec<(usize, String)> = Vec::new();
+
+    for (conn, s) in waiters {
+        for waiter in s {
+            let mut new_msg = msg.clone();
+            if !waiter.is_none() {
+                new_msg += &format!(" [sub_key={}]", waiter.unwrap());
+            }
+            new_msg += "\r\n";
+            msgs.push((*conn, new_msg));
+        }
+    }
+
+    send(msgs);
+}
+
+fn send(msgs: Vec<(usize, String)>) {
+    if let Some(transport) = TRANSPORT.get() {
+        transport.send(msgs);
+    }
+}
+
+fn reply(conn: usize, msg: String) {
+    if let Some(transport) = TRANSPORT.get() {
+        transport.reply(conn, msg);
+    }
+}
+
+fn schedule(at: Instant, iekey: &str) {
+    TIMER_THREAD.call_once(|| {
+        thread::spawn(timer_loop);
+    });
+    let (timers, cvar) = &*TIMERS;
+    let mut timers = timers.lock().unwrap();
+    if timers.due.get(iekey).is_some_and(|due| *due <= at) {
+        /* The earlier timer comes first and schedules this one from on_timer. */
+        return;
+    }
+    timers.due.insert(iekey.to_string(), at);
+    timers.heap.push(Reverse((at, iekey.to_string())));
+    cvar.notify_one();
+}
+
+/* Timers only name a key; on_timer works out what is due, and those superseded by an earlier one are skipped. */
+fn timer_loop() {
+    let (timers, cvar) = &*TIMERS;
+    let mut t = timers.lock().unwrap();
+    loop {
+        let now = Instant::now();
+        match t.heap.peek() {
+            None => t = cvar.wait(t).unwrap(),
+            Some(Reverse((at, _))) if *at > now => {
+                let timeout = *at - now;
+                t = cvar.wait_timeout(t, timeout).unwrap().0;
+            },
+            Some(_) => {
+                let Reverse((at, iekey)) = t.heap.pop().unwrap();
+                if t.due.get(&iekey) != Some(&at) {
+                    continue;
+                }
+                t.due.remove(&iekey);
+                drop(t);
+                on_timer(&iekey);
+                t = timers.lock().unwrap();
+            }
+        }
+    }
+}
+
+/*
+ * Answers parked waits past their deadline and releases holds whose lease ran
+ * out: the former holder is told `LEASE_EXPIRED <key>`, and the lock is handed
+ * to its queue or waiters are told `UNLOCKED` as if it had been unlocked.
+ */
+fn on_timer(iekey: &str) {
+    let mut timed_out: Vec<usize> = Vec::new();
+    {
+        let mut iek = IEK.lock().unwrap();
+        let data = match iek.get_mut(iekey) {
+            Some(data) => data,
+            None => return
+        };
+
+        let cur_time = Instant::now();
+        let (expired, released) = expire(&mut data.state, cur_time, &mut timed_out);
+        send(notices("LEASE_EXPIRED", iekey, &expired));
+        if after_release(iekey, data, released) {
+            iek.remove(iekey);
+        } else if let Some(at) = next_deadline(&data.state, cur_time) {
+            schedule(at, iekey);
+        }
+    }
+
+    for conn in timed_out {
+        reply(conn, "TIMEOUT\r\n".to_string());
+    }
+}
+
+/*
+ * Takes away the holds whose lease ran out, returning who held them and
+ * whether anything was released, and moves parked waits past their deadline
+ * to `timed_out`.
+ */
+fn expire(state: &mut IEKState, now: Instant, timed_out: &mut Vec<usize>) -> (Vec<(usize, Option<i32>)>, bool) {
+    let mut expired: Vec<(usize, Option<i32>)> = Vec::new();
+    let mut released = false;
+    match state {
+        IEKState::Lock(l) => {
+            l.queue.retain(|w| {
+                if w.deadline.is_some_and(|d| d <= now) {
+                    timed_out.push(w.conn);
+                    return false;
+                }
+                return true;
+            });
+            if let Some(h) = l.hold.take_if(|h| h.lease_time <= now) {
+                expired.extend(h.conn.map(|c| (c, h.sub_key)));
+                released = true;
+            }
+        },
+        IEKState::RWLock(rw) => {
+            if let Some((writer, _)) = rw.writer.take_if(|(_, h)| h.lease_time <= now) {
+                expired.push(writer);
+            }
+            rw.readers.retain(|reader, h| {
+                if h.lease_time <= now {
+                    expired.push(*reader);
+                    return false;
+                }
+                return true;
+            });
+        },
+        IEKState::Semaphore(s) => {
+            s.holders.retain(|conn, (h, _)| {
+                if h.lease_time <= now {
+                    expired.push((*conn, None));
+                    return false;
+                }
+                return true;
+            });
+        },
+        IEKState::Election(e) => {
+            if let Some(leader) = e.leader.take_if(|l| l.lease_time <= now) {
+                expired.push((leader.conn, None));
+            }
+        },
+        IEKState::Flight(f) => {
+            f.followers.retain(|w| {
+                if w.deadline <= now {
+                    timed_out.push(w.conn);
+                    return false;
+                }
+                return true;
+            });
+            if f.lease_time <= now {
+                expired.push((f.leader, None));
+            }
+        },
+        IEKState::Condition(queue) => {
+            queue.retain(|w| {
+                if w.deadline <= now {
+                    timed_out.push(w.conn);
+                    return false;
+                }
+                return true;
+            });
+        },
+        _ => ()
+    }
+    released |= !expired.is_empty();
+    return (expired, released);
+}
+
+/* The earliest lease end, parked deadline or end of an open latch still ahead on the key. */
+fn next_deadline(state: &IEKState, now: Instant) -> Option<Instant> {
+    let parked: Vec<Instant> = match state {
+        IEKState::Lock(l) => l.queue.iter().filter_map(|w| w.deadline).collect(),
+        IEKState::Condition(queue) | IEKState::Flight(FlightState { followers: queue, .. }) =>
+            queue.iter().map(|w| w.deadline).collect(),
+        IEKState::Latch(l) => l.open_until.into_iter().collect(),
+        _ => Vec::new()
+    };
+    return state.live_leases(now).into_iter().chain(parked).min();
+}
+
+/* `<what> <key> [sub_key=N]` for each of `holders`. */
+fn notices(what: &str, iekey: &str, holders: &[(usize, Option<i32>)]) -> Vec<(usize, String)> {
+    return holders.iter().map(|(conn, sub_key)| {
+        let mut msg = format!("{} {}", what, iekey);
+        if let Some(s) = sub_key {
+            msg += &format!(" [sub_key={}]", s);
+        }
+        return (*conn, msg + "\r\n");
+    }).collect();
+}
+
+/*
+ * Moves a key along after holds were taken away without an unlock: hands a
+ * lock to its queue or tells the waiters. Returns true once the key can be
+ * dropped.
+ */
+fn after_release(iekey: &str, data: &mut IEKData, released: bool) -> bool {
+    let IEKData { state, waiters } = data;
+    match state {
+        IEKState::Lock(l) if l.hold.is_none() => {
+            if hand_off(iekey, l, waiters) {
+                /* Handed straight to the next queued waiter. */
+            } else if waiters.is_empty() {
+                return true;
+            } else if released {
+                do_publish(waiters, format!("UNLOCKED {}", iekey));
+            }
+        },
+        IEKState::Election(e) if e.leader.is_none() => return !elect_next(iekey, e, None),
+        IEKState::Condition(queue) => return queue.is_empty(),
+        IEKState::Latch(l) => return l.open_until.is_some_and(|t| t <= Instant::now()),
+        IEKState::Flight(f) if released => {
+            fail_flight(f);
+            return true;
+        },
+        IEKState::RateLimit(b) => {
+            b.refill(Instant::now());
+            if b.tokens < b.capacity {
+                schedule(b.full_at(), iekey);
+                return false;
+            }
+            return waiters.is_empty();
+        },
+        IEKState::RWLock(rw) if released => {
+            if waiters.is_empty() && rw.readers.is_empty() && rw.writer.is_none() {
+                return true;
+            } else if !waiters.is_empty() && rw.is_free(Instant::now()) {
+                do_publish(waiters, format!("UNLOCKED {}", iekey));
+            }
+        },
+        IEKState::Semaphore(s) if released => {
+            if waiters.is_empty() && s.holders.is_empty() {
+                return true;
+            } else if !waiters.is_empty() {
+                do_publish(waiters, format!("UNLOCKED {}", iekey));
+            }
+        },
+        _ => ()
+    }
+    return false;
+}
+
+/* Drops the connection's parked waits without answering them, as memcached aborts the command. */
+pub fn abort_command(conn: usize) {
+    let keys: Vec<String> = match CONN.lock().unwrap().get(&conn) {
+        Some(s) => s.iter().cloned().collect(),
+        None => return
+    };
+
+    let mut iek = IEK.lock().unwrap();
+    for iekey in keys {
+        match iek.get_mut(&iekey).map(|d| &mut d.state) {
+            Some(IEKState::Lock(l)) => l.queue.retain(|w| w.conn != conn || w.deadline.is_none()),
+            Some(IEKState::Condition(queue)) => queue.retain(|w| w.conn != conn),
+            Some(IEKState::Flight(f)) => f.followers.retain(|w| w.conn != conn),
+            _ => ()
+        }
+    }
+}
+
+/*
+ * Grants a released Lock straight to the first queued waiter, pushing it
+ * `LOCKED <key> <token>` or, if it is parked, answering its `lock` with
+ * `OK <token>`. Returns false when nobody is queued.
+ */
+fn hand_off(iekey: &str, lock: &mut LockState, waiters: &mut Waiters) -> bool {
+    let w = match lock.queue.pop_front() {
+        Some(w) => w,
+        None => return false
+    };
+    let fence = next_fence();
+    let lease_time = Instant::now() + w.lease;
+    lock.hold = Some(Hold {
+        conn: Some(w.conn),
+        token: w.token,
+        on_disconnect: w.on_disconnect,
+        sub_key: w.sub_key,
+        lease_time,
+        fence,
+        acquired: SystemTime::now(),
+        hold_count: 1
+    });
+    remove_waiter(waiters, w.conn, w.sub_key);
+    lock.retrying.remove(&(w.conn, w.sub_key));
+    schedule(lease_time, iekey);
+
+    if w.deadline.is_some() {
+        reply(w.conn, format!("OK {}\r\n", fence));
+        return true;
+    }
+    let mut msg = format!("LOCKED {} {}", iekey, fence);
+    if let Some(s) = w.sub_key {
+        msg += &format!(" [sub_key={}]", s);
+    }
+    send(vec![(w.conn, msg + "\r\n")]);
+    return true;
+}
+
+/*
+ * Whether pub/sub may use the key as a channel. Locks nobody holds share
+ * their key with a channel, so their waiters hear it too; latches and
+ * barriers are never taken for channels.
+ */
+fn takes_channel(data: &IEKData) -> bool {
+    match &data.state {
+        IEKState::PubSub => return true,
+        IEKState::Lock(_) | IEKState::RWLock(_) | IEKState::Semaphore(_) =>
+            return data.state.is_free(Instant::now()),
+        _ => return false
+    }
+}
+
+fn process_publish_command(iekey: String, msg: String) -> String {
+    let mut result = "PUBLISHED\r\n".to_string();
+
+    {
+        let mut iek = IEK.lock().unwrap();
+        match iek.get_mut(&iekey) {
+            Some(iekdata) => {
+                if !takes_channel(iekdata) {
+                    return "TYPE_MISMATCH\r\n".to_string();
+                }
+                do_publish(&iekdata.waiters, format!("CHANNEL {} {}", iekey, msg));
+            }
+            None => result = "NOT_FOUND\r\n".to_string()
+        }
+    }
+    return result;
+}
+
+fn process_subscribe_command(conn: usize, iekey: String) -> String {
+    let result = format!("{} SUCCESS\r\n", iekey);
+
+    {
+        let mut iek = IEK.lock().unwrap();
+        match iek.entry(iekey.clone()) {
+            Entry::Vacant(e) => {
+                e.insert(IEKData {
+                    waiters: [(conn, None)].into_iter()
+                                           .map(|(k, v)| (k, HashSet::from([v])))
+                                           .collect(),
+                    ..IEKData::new(IEKState::PubSub)
+                });
+            },
+            Entry::Occupied(mut e) => {
+                let data = e.get_mut();
+                if !takes_channel(data) {
+                    return format!("{} TYPE_MISMATCH\r\n", iekey);
+                }
+                data.waiters.entry(conn)
+                            .or_insert_with(HashSet::new)
+                            .insert(None);
+            }
+        }
+    }
+
+    {
+        let mut conns = CONN.lock().unwrap();
+        let s = match conns.entry(conn) {
+            Entry::Vacant(e) => e.insert(HashSet::new()),
+            Entry::Occupied(e) => e.into_mut()
+        };
+
+        if !s.contains(&iekey) {
+            s.insert(iekey);
+        }
+    }
+
+    return result;
+}
+
+fn process_unsubscribe_command(conn: usize, iekey: String) -> String {
+    let mut iek = IEK.lock().unwrap();
+    let mut result = "SUCCESS\r\n".to_string();
+
+    match iek.get_mut(&iekey) {
+        Some(iekdata) => {
+            if iekdata.waiters.remove(&conn).is_none() {
+                result = "NOT_SUBSCRIBED\r\n".to_string();
+            }
+        },
+        _ => result = "NOT_FOUND_CHANNEL\r\n".to_string()
+    }
+
+    return result;
+}
+
+/*
+ * Whether `waiter` is waiting on the key: in its queue, or turned away lately
+ * enough to be retrying still.
+ */
+fn is_waiting(data: &IEKData, waiter: (usize, Option<i32>), now: Instant) -> bool {
+    match &data.state {
+        IEKState::Lock(l) => return l.retrying.get(&waiter).is_some_and(|until| *until >= now) ||
+                                    l.queue.iter().any(|w| (w.conn, w.sub_key) == waiter),
+        IEKState::RWLock(rw) => return rw.retrying.get(&waiter).is_some_and(|until| *until >= now) ||
+                                       rw.writer_waiters.get(&waiter).is_some_and(|until| *until >= now),
+        IEKState::Flight(f) => return waiter.1.is_none() && f.followers.iter().any(|w| w.conn == waiter.0),
+        _ => return false
+    }
+}
+
+/* Who holds the key exclusively, as far as the wait-for graph goes. */
+fn exclusive_owner(data: &IEKData, now: Instant) -> Option<(usize, Option<i32>)> {
+    match &data.state {
+        IEKState::Lock(l) => return l.owned(now).and_then(|h| h.conn.map(|c| (c, h.sub_key))),
+        IEKState::RWLock(rw) => return rw.writer.as_ref().filter(|(_, h)| h.lease_time >= now).map(|(w, _)| *w),
+        IEKState::Election(e) => return e.leader.as_ref().filter(|l| l.lease_time >= now).map(|l| (l.conn, None)),
+        IEKState::Flight(f) if f.lease_time >= now => return Some((f.leader, None)),
+        _ => return None
+    }
+}
+
+/*
+ * Walks the wait-for graph from `iekey`: its owner may wait on other keys, whose
+ * owners may wait on others again. If the walk gets back to `requester`, `path`
+ * is left holding the keys of the cycle.
+ */
+fn leads_to(iek: &HashMap<String, IEKData>, conns: &HashMap<usize, HashSet<String>>, iekey: &str,
+            requester: (usize, Option<i32>), seen: &mut HashSet<(usize, Option<i32>)>,
+            path: &mut Vec<String>) -> bool {
+    let owner = match iek.get(iekey).and_then(|d| exclusive_owner(d, Instant::now())) {
+        Some(owner) => owner,
+        None => return false
+    };
+    if owner == requester {
+        return true;
+    }
+    if !seen.insert(owner) {
+        return false;
+    }
+
+    for next in conns.get(&owner.0).into_iter().flatten() {
+        if next == iekey || !iek.get(next).is_some_and(|d| is_waiting(d, owner, Instant::now())) {
+            continue;
+        }
+        path.push(next.clone());
+        if leads_to(iek, conns, next, requester, seen, path) {
+            return true;
+        }
+        path.pop();
+    }
+    return false;
+}
+
+/* The keys of the circular wait `conn` would close by waiting on `iekey`, if any. */
+fn find_deadlock(iek: &HashMap<String, IEKData>, conn: usize, sub_key: Option<i32>,
+                 iekey: &str) -> Option<Vec<String>> {
+    let conns = CONN.lock().unwrap();
+    let mut path = vec![iekey.to_string()];
+    if leads_to(iek, &conns, iekey, (conn, sub_key), &mut HashSet::new(), &mut path) {
+        return Some(path);
+    }
+    return None;
+}
+
+/*
+ * With `owner=<token>`, the lock belongs to the token instead of the
+ * connection: any connection presenting it renews or unlocks it, and it
+ * outlives the connection that took it until the lease runs out.
+ * With `disconnect=release|keep|<grace secs>`, the lock overrides the global
+ * policy for when its connection goes away.
+ * With `reentrant`, taking a lock the connection already owns counts one more
+ * hold instead of only extending the lease; unlock then has to be called as
+ * many times before the lock is released.
+ * With `fair`, a turned away caller is queued and handed the lock in arrival
+ * order instead of racing the other waiters after UNLOCKED.
+ * With `wait`, it is queued the same way but parked: the reply is left empty
+ * and sent once the lock is handed over, or as TIMEOUT after `wait` seconds.
+ */
+#[allow(clippy::too_many_arguments)]
+fn process_lock_command(conn: usize, iekey: String, sub_key: Option<i32>, lease_time: f64,
+                        reentrant: bool, fair: bool, wait: Option<f64>, token: Option<&str>,
+                        on_disconnect: Option<DisconnectPolicy>) -> String {
+    let mut result = String::new();
+    {
+        let cur_time = Instant::now();
+        let exp_time = cur_time + Duration::from_millis((lease_time * 1000.0) as u64);
+        let mut iek = IEK.lock().unwrap();
+        /* Waiting here would close a circular wait: refuse instead of waiting for the leases. */
+        if iek.get(&iekey).is_some_and(|d| matches!(&d.state, IEKState::Lock(l)
+                if l.owned(cur_time).is_some_and(|h| !h.owned_by(conn, sub_key, token)))) &&
+            let Some(keys) = find_deadlock(&iek, conn, sub_key, &iekey) {
+            if let Some(data) = iek.get_mut(&iekey) && let IEKState::Lock(l) = &mut data.state {
+                remove_waiter(&mut data.waiters, conn, sub_key);
+                l.queue.retain(|w| (w.conn, w.sub_key) != (conn, sub_key));
+                l.retrying.remove(&(conn, sub_key));
+            }
+            return format!("DEADLOCK {}\r\n", keys.join(" "));
+        }
+
+        let IEKData { state, waiters } = iek.entry(iekey.clone())
+                                            .or_insert_with(|| IEKData::new(IEKState::Lock(LockState::default())));
+        let l = match state {
+            IEKState::Lock(l) => l,
+            _ => return "TYPE_MISMATCH\r\n".to_string()
+        };
+        /* The lease ran out with a queue behind it: the head of the queue is next. */
+        if l.owned(cur_time).is_none() &&
+            l.queue.front().is_some_and(|w| (w.conn, w.sub_key) != (conn, sub_key)) {
+            hand_off(&iekey, l, waiters);
+        }
+        if l.owned(cur_time).is_none() {
+            l.queue.retain(|w| (w.conn, w.sub_key) != (conn, sub_key));
+            l.retrying.retain(|w, until| *w != (conn, sub_key) && *until >= cur_time);
+            let fence = next_fence();
+            l.hold = Some(Hold {
+                conn: Some(conn),
+                token: token.map(|t| t.to_string()),
+                on_disconnect,
+                sub_key,
+                lease_time: exp_time,
+                fence,
+                acquired: SystemTime::now(),
+                hold_count: 1
+            });
+            remove_waiter(waiters, conn, sub_key);
+            result = format!("OK {}\r\n", fence);
+            schedule(exp_time, &iekey);
+        } else if let Some(h) = l.hold.as_mut() && h.owned_by(conn, sub_key, token) {
+            /* Pushes follow the connection that last presented the token. */
+            let former = h.conn.replace(conn);
+            let former_stays = former.is_some_and(|f| waiters.contains_key(&f) ||
+                                                      l.queue.iter().any(|w| w.conn == f));
+            rebind_conn_key(&iekey, former, conn, former_stays);
+            h.lease_time = exp_time;
+            if on_disconnect.is_some() {
+                h.on_disconnect = on_disconnect;
+            }
+            if reentrant {
+                h.hold_count += 1;
+            }
+            result = format!("OWNED {}\r\n", h.fence);
+            schedule(exp_time, &iekey);
+        } else {
+            let lease = exp_time - cur_time;
+            let deadline = wait.map(|t| cur_time + Duration::from_millis((t * 1000.0) as u64));
+            match l.queue.iter_mut().find(|w| (w.conn, w.sub_key) == (conn, sub_key)) {
+                Some(w) => {
+                    w.lease = lease;
+                    w.deadline = deadline;
+                    w.token = token.map(|t| t.to_string());
+                    w.on_disconnect = on_disconnect;
+                },
+                None if fair || deadline.is_some() =>
+                    l.queue.push_back(Waiter {
+                        conn,
+                        sub_key,
+                        lease,
+                        deadline,
+                        token: token.map(|t| t.to_string()),
+                        on_disconnect
+                    }),
+                None => add_waiter(waiters, conn, sub_key)
+            }
+            if let Some(d) = deadline {
+                /* Parked: answered by hand_off or on_timer. */
+                schedule(d, &iekey);
+            } else {
+                let remaining = time_left(l.hold.iter().map(|h| h.lease_time), cur_time);
+                l.retrying.insert((conn, sub_key), cur_time + remaining + RETRY_GRACE);
+                result += &format!("RETRY_LATER {:.3}\n", remaining.as_millis() as f64 / 1000.0);
+            }
+        }
+    }
+
+    {
+        let mut conns = CONN.lock().unwrap();
+        let s = match conns.entry(conn) {
+            Entry::Vacant(e) => e.insert(HashSet::new()),
+            Entry::Occupied(e) => e.into_mut()
+        };
+
+        if !s.contains(&iekey) {
+            s.insert(iekey);
+        }
+    }
+
+    return result;
+}
+
+fn process_unlock_command(conn: usize, iekey: String, sub_key: Option<i32>, fence: Option<u64>,
+                          token: Option<&str>) -> String {
+    let result: String;
+
+    {
+        let mut iek = IEK.lock().unwrap();
+        match iek.get_mut(&iekey) {
+            Some(iekdata) => {
+                let IEKData { state, waiters } = iekdata;
+                match state {
+                    IEKState::RWLock(rw) => {
+                        let (r, drop_key) = release_rwlock(&iekey, rw, waiters, conn, sub_key, fence);
+                        if drop_key {
+                            iek.remove(&iekey);
+                        }
+                        result = r;
+                    },
+                    IEKState::Lock(l) => {
+                        if let Some(h) = l.hold.as_mut() && h.lease_time >= Instant::now() &&
+                            h.owned_by(conn, sub_key, token) && fence.is_none_or(|f| f == h.fence) {
+                            h.hold_count -= 1;
+                            if h.hold_count > 0 {
+                                return format!("HELD {}\r\n", h.hold_count);
+                            }
+                            l.hold = None;
+                            if hand_off(&iekey, l, waiters) {
+                                /* Handed straight to the next queued waiter. */
+                            } else if waiters.is_empty() {
+                                iek.remove(&iekey);
+                            } else {
+                                do_publish(waiters, format!("UNLOCKED {}", iekey));
+                            }
+                            result = "SUCCESS\r\n".to_string();
+                        } else if let Some(i) = l.queue.iter().position(|w| (w.conn, w.sub_key) == (conn, sub_key)) {
+                            /* A queued waiter giving up its place. */
+                            l.queue.remove(i);
+                            result = "DEQUEUED\r\n".to_string();
+                        } else {
+                            result = "NOT_OWNED\r\n".to_string();
+                        }
+                    },
+                    _ => return "TYPE_MISMATCH\r\n".to_string()
+                }
+            },
+            None => result = "NOT_FOUND\r\n".to_string()
+        }
+    }
+
+    {
+        let mut conns = CONN.lock().unwrap();
+        let s = match conns.entry(conn) {
+            Entry::Vacant(e) => e.insert(HashSet::new()),
+            Entry::Occupied(e) => e.into_mut()
+        };
+
+        if  s.contains(&iekey) {
+            s.remove(&iekey);
+        }
+    }
+
+    return result;
+}
+
+/* Time until the last of `leases` runs out, zero if they all have. */
+fn time_left(leases: impl Iterator<Item = Instant>, now: Instant) -> Duration {
+    return leases.max().map_or(Duration::ZERO, |t| t.saturating_duration_since(now));
+}
+
+/* Time until every current holder of the key, writer or reader, has let go at the latest. */
+fn holders_remaining(state: &IEKState, now: Instant) -> Duration {
+    return time_left(state.live_leases(now).into_iter(), now);
+}
+
+fn add_waiter(waiters: &mut Waiters, conn: usize, sub_key: Option<i32>) {
+    waiters.entry(conn)
+           .or_default()
+           .insert(sub_key);
+}
+
+fn remove_waiter(waiters: &mut Waiters, conn: usize, sub_key: Option<i32>) {
+    if let Some(h) = waiters.get_mut(&conn) {
+        h.remove(&sub_key);
+        if h.is_empty() {
+            waiters.remove(&conn);
+        }
+    }
+}
+
+fn add_conn_key(conn: usize, iekey: String) {
+    let mut conns = CONN.lock().unwrap();
+    conns.entry(conn)
+         .or_default()
+         .insert(iekey);
+}
+
+/*
+ * A lock presented by token from another connection, or transferred to it,
+ * now pushes there: the key moves to that connection in CONN, so its
+ * disconnect is seen, and leaves the former one unless it still waits on the key.
+ */
+fn rebind_conn_key(iekey: &str, former: Option<usize>, conn: usize, former_stays: bool) {
+    let mut conns = CONN.lock().unwrap();
+    if let Some(former) = former && former != conn && !former_stays &&
+        let Some(s) = conns.get_mut(&former) {
+        s.remove(iekey);
+    }
+    conns.entry(conn)
+         .or_default()
+         .insert(iekey.to_string());
+}
+
+fn new_rwlock() -> IEKData {
+    return IEKData::new(IEKState::RWLock(RWLockState::default()));
+}
+
+/*
+ * Shared hold of an RWLock. Readers are turned away while a writer holds the
+ * lock or waits for it, so a steady stream of readers cannot starve writers.
+ * The writer itself may take a read hold, which downgrades its write hold.
+ */
+fn process_rlock_command(conn: usize, iekey: String, sub_key: Option<i32>, lease_time: f64) -> String {
+    let result: String;
+    {
+        let cur_time = Instant::now();
+        let exp_time = cur_time + Duration::from_millis((lease_time * 1000.0) as u64);
+        let mut iek = IEK.lock().unwrap();
+        let IEKData { state, waiters } = iek.entry(iekey.clone()).or_insert_with(new_rwlock);
+        let rw = match state {
+            IEKState::RWLock(rw) => rw,
+            _ => return "TYPE_MISMATCH\r\n".to_string()
+        };
+
+        rw.purge_expired(cur_time);
+        let holder = (conn, sub_key);
+        let write_held = rw.write_held(cur_time);
+        if write_held && rw.writer.as_ref().is_some_and(|(w, _)| *w == holder) {
+            rw.writer = None;
+            let fence = next_fence();
+            rw.readers.insert(holder, Holder {
+                lease_time: exp_time,
+                fence,
+                acquired: SystemTime::now()
+            });
+            if !waiters.is_empty() {
+                do_publish(waiters, format!("UNLOCKED {}", iekey));
+            }
+            result = format!("OK {}\r\n", fence);
+            schedule(exp_time, &iekey);
+        } else if let Some(h) = rw.readers.get_mut(&holder) {
+            h.lease_time = exp_time;
+            result = format!("OWNED {}\r\n", h.fence);
+            schedule(exp_time, &iekey);
+        } else if write_held || !rw.writer_waiters.is_empty() {
+            add_waiter(waiters, conn, sub_key);
+            let remaining = time_left(rw.leases(), cur_time);
+            rw.retrying.insert(holder, cur_time + remaining + RETRY_GRACE);
+            result = format!("RETRY_LATER {:.3}\n", remaining.as_millis() as f64 / 1000.0);
+        } else {
+            let fence = next_fence();
+            rw.readers.insert(holder, Holder {
+                lease_time: exp_time,
+                fence,
+                acquired: SystemTime::now()
+            });
+            remove_waiter(waiters, conn, sub_key);
+            rw.retrying.remove(&holder);
+            result = format!("OK {}\r\n", fence);
+            schedule(exp_time, &iekey);
+        }
+    }
+
+    add_conn_key(conn, iekey);
+    return result;
+}
+
+/*
+ * Exclusive hold of an RWLock. A reader that is the only one left upgrades its
+ * read hold; otherwise the writer is remembered so new readers wait behind it.
+ */
+fn process_wlock_command(conn: usize, iekey: String, sub_key: Option<i32>, lease_time: f64) -> String {
+    let result: String;
+    {
+        let cur_time = Instant::now();
+        let exp_time = cur_time + Duration::from_millis((lease_time * 1000.0) as u64);
+        let mut iek = IEK.lock().unwrap();
+        let IEKData { state, waiters } = iek.entry(iekey.clone()).or_insert_with(new_rwlock);
+        let rw = match state {
+            IEKState::RWLock(rw) => rw,
+            _ => return "TYPE_MISMATCH\r\n".to_string()
+        };
+
+        rw.purge_expired(cur_time);
+        let holder = (conn, sub_key);
+        let write_held = rw.write_held(cur_time);
+        let other_readers = rw.readers.keys().any(|h| *h != holder);
+        if let Some((w, h)) = rw.writer.as_mut() && write_held && *w == holder {
+            h.lease_time = exp_time;
+            result = format!("OWNED {}\r\n", h.fence);
+            schedule(exp_time, &iekey);
+        } else if write_held || other_readers {
+            let remaining = time_left(rw.leases(), cur_time);
+            rw.writer_waiters.insert(holder, cur_time + remaining + WRITER_WAIT_GRACE);
+            add_waiter(waiters, conn, sub_key);
+            result = format!("RETRY_LATER {:.3}\n", remaining.as_millis() as f64 / 1000.0);
+        } else {
+            rw.readers.remove(&holder);
+            rw.writer_waiters.remove(&holder);
+            rw.retrying.remove(&holder);
+            let fence = next_fence();
+            rw.writer = Some((holder, Holder {
+                lease_time: exp_time,
+                fence,
+                acquired: SystemTime::now()
+            }));
+            remove_waiter(waiters, conn, sub_key);
+            result = format!("OK {}\r\n", fence);
+            schedule(exp_time, &iekey);
+        }
+    }
+
+    add_conn_key(conn, iekey);
+    return result;
+}
+
+/*
+ * Releases whichever hold the connection has on an RWLock, the write hold first.
+ * The returned flag tells the caller to drop the key once nobody uses it anymore.
+ */
+fn release_rwlock(iekey: &str, rw: &mut RWLockState, waiters: &Waiters, conn: usize,
+                  sub_key: Option<i32>, fence: Option<u64>) -> (String, bool) {
+    let cur_time = Instant::now();
+    rw.purge_expired(cur_time);
+
+    let holder = (conn, sub_key);
+    if rw.writer.as_ref().is_some_and(|(w, h)| *w == holder && h.lease_time >= cur_time &&
+                                               fence.is_none_or(|f| f == h.fence)) {
+        rw.writer = None;
+    } else if rw.readers.get(&holder).is_some_and(|h| fence.is_none_or(|f| f == h.fence)) {
+        rw.readers.remove(&holder);
+    } else {
+        return ("NOT_OWNED\r\n".to_string(), false);
+    }
+
+    if !rw.is_free(cur_time) {
+        return ("SUCCESS\r\n".to_string(), false);
+    }
+    if waiters.is_empty() && rw.writer_waiters.is_empty() {
+        return ("SUCCESS\r\n".to_string(), true);
+    }
+    do_publish(waiters, format!("UNLOCKED {}", iekey));
+    return ("SUCCESS\r\n".to_string(), false);
+}
+
+/*
+ * Takes `permits` out of a semaphore of `max` permits. Holders are counted per
+ * connection: acquiring again renews the lease and resizes the hold to `permits`.
+ * Permits of holders whose lease ran out are reclaimed before counting.
+ */
+fn process_sem_acquire_command(conn: usize, iekey: String, permits: u32, max: u32,
+                               lease_time: f64) -> String {
+    let result: String;
+    {
+        let cur_time = Instant::now();
+        let exp_time = cur_time + Duration::from_millis((lease_time * 1000.0) as u64);
+        let mut iek = IEK.lock().unwrap();
+        let IEKData { state, waiters } = iek.entry(iekey.clone()).or_insert_with(|| {
+            IEKData::new(IEKState::Semaphore(SemaphoreState { max_permits: max, holders: HashMap::new() }))
+        });
+        let s = match state {
+            IEKState::Semaphore(s) => s,
+            _ => return "TYPE_MISMATCH\r\n".to_string()
+        };
+        if s.max_permits != max {
+            return "MAX_MISMATCH\r\n".to_string();
+        }
+
+        s.holders.retain(|_, (h, _)| h.lease_time >= cur_time);
+        /* Summed wide: holds of up to max permits each could overflow a u32. */
+        let in_use: u64 = s.holders.iter()
+                                   .filter(|(c, _)| **c != conn)
+                                   .map(|(_, (_, p))| *p as u64)
+                                   .sum();
+        if in_use + permits as u64 <= max as u64 {
+            match s.holders.get_mut(&conn) {
+                Some((h, held)) => {
+                    h.lease_time = exp_time;
+                    *held = permits;
+                    result = format!("OWNED {}\r\n", h.fence);
+                    schedule(exp_time, &iekey);
+                },
+                None => {
+                    let fence = next_fence();
+                    s.holders.insert(conn, (Holder {
+                        lease_time: exp_time,
+                        fence,
+                        acquired: SystemTime::now()
+                    }, permits));
+                    remove_waiter(waiters, conn, None);
+                    result = format!("OK {}\r\n", fence);
+                    schedule(exp_time, &iekey);
+                }
+            }
+        } else {
+            add_waiter(waiters, conn, None);
+            let next_free = s.holders.values()
+                                     .map(|(h, _)| h.lease_time)
+                                     .min()
+                                     .unwrap_or(cur_time);
+            let remaining: f64 = (next_free - cur_time).as_millis() as f64 / 1000.0;
+            result = format!("RETRY_LATER {:.3}\n", remaining);
+        }
+    }
+
+    add_conn_key(conn, iekey);
+    return result;
+}
+
+/* Gives back `permits` of the connection's hold, or all of it; waiters are told a permit is free. */
+fn process_sem_release_command(conn: usize, iekey: String, permits: Option<u32>) -> String {
+    let result: String;
+    {
+        let mut iek = IEK.lock().unwrap();
+        let IEKData { state, waiters } = match iek.get_mut(&iekey) {
+            Some(data) => data,
+            None => return "NOT_FOUND\r\n".to_string()
+        };
+        let s = match state {
+            IEKState::Semaphore(s) => s,
+            _ => return "TYPE_MISMATCH\r\n".to_string()
+        };
+
+        let cur_time = Instant::now();
+        s.holders.retain(|_, (h, _)| h.lease_time >= cur_time);
+        let held = match s.holders.get_mut(&conn) {
+            Some((_, held)) => held,
+            None => return "NOT_OWNED\r\n".to_string()
+        };
+        *held -= permits.unwrap_or(*held).min(*held);
+        if *held > 0 {
+            result = format!("HELD {}\r\n", held);
+        } else {
+            s.holders.remove(&conn);
+            result = "SUCCESS\r\n".to_string();
+        }
+
+        if waiters.is_empty() && s.holders.is_empty() {
+            iek.remove(&iekey);
+        } else if !waiters.is_empty() {
+            do_publish(waiters, format!("UNLOCKED {}", iekey));
+        }
+    }
+
+    if result == "SUCCESS\r\n" {
+        let mut conns = CONN.lock().unwrap();
+        if let Some(s) = conns.get_mut(&conn) {
+            s.remove(&iekey);
+        }
+    }
+    return result;
+}
+
+/*
+ * Extends the lease of a hold the connection still has: an exclusive lock,
+ * either side of an RWLock or a semaphore hold. Unlike `lock` it never takes
+ * the lock anew once the lease has run out.
+ */
+fn process_relock_command(conn: usize, iekey: String, sub_key: Option<i32>, lease_time: f64,
+                          token: Option<&str>) -> String {
+    let fence: u64;
+    let cur_time = Instant::now();
+    let exp_time = cur_time + Duration::from_millis((lease_time * 1000.0) as u64);
+    {
+        let mut iek = IEK.lock().unwrap();
+        let IEKData { state, waiters } = match iek.get_mut(&iekey) {
+            Some(data) => data,
+            None => return "NOT_FOUND\r\n".to_string()
+        };
+
+        let holder = (conn, sub_key);
+        match state {
+            IEKState::PubSub => return "TYPE_MISMATCH\r\n".to_string(),
+            IEKState::Lock(l) => match l.hold.as_mut() {
+                Some(h) if h.lease_time >= cur_time && h.owned_by(conn, sub_key, token) => {
+                    let former = h.conn.replace(conn);
+                    let former_stays = former.is_some_and(|f| waiters.contains_key(&f) ||
+                                                              l.queue.iter().any(|w| w.conn == f));
+                    rebind_conn_key(&iekey, former, conn, former_stays);
+                    h.lease_time = exp_time;
+                    fence = h.fence;
+                },
+                _ => return "NOT_OWNED\r\n".to_string()
+            },
+            IEKState::RWLock(rw) => {
+                let h = match rw.writer.as_mut() {
+                    Some((w, h)) if *w == holder => Some(h),
+                    _ => rw.readers.get_mut(&holder)
+                };
+                match h {
+                    Some(h) if h.lease_time >= cur_time => {
+                        h.lease_time = exp_time;
+                        fence = h.fence;
+                    },
+                    _ => return "NOT_OWNED\r\n".to_string()
+                }
+            },
+            IEKState::Semaphore(s) => match s.holders.get_mut(&conn) {
+                Some((h, _)) if sub_key.is_none() && h.lease_time >= cur_time => {
+                    h.lease_time = exp_time;
+                    fence = h.fence;
+                },
+                _ => return "NOT_OWNED\r\n".to_string()
+            },
+            _ => return "NOT_OWNED\r\n".to_string()
+        }
+    }
+
+    schedule(exp_time, &iekey);
+    return format!("OWNED {}\r\n", fence);
+}
+
+/* Seconds until the key is free at the latest, as far as current holders go. */
+fn process_lockttl_command(iekey: String) -> String {
+    let iek = IEK.lock().unwrap();
+    let cur_time = Instant::now();
+    match iek.get(&iekey) {
+        Some(data) if matches!(data.state, IEKState::PubSub) => return "TYPE_MISMATCH\r\n".to_string(),
+        Some(data) if !data.state.is_free(cur_time) => {
+            let remaining: f64 = holders_remaining(&data.state, cur_time).as_millis() as f64 / 1000.0;
+            return format!("TTL {:.3}\r\n", remaining);
+        },
+        _ => return "NOT_FOUND\r\n".to_string()
+    }
+}
+
+/*
+ * Takes every key as an exclusive lock, or none of them. If any key is held by
+ * someone else the caller waits for UNLOCKED on those keys and is told which
+ * ones with `CONTENDED <key> ...`; on success the fencing tokens come back in
+ * key order as `OK <token> ...`. Keys the caller already owns are renewed.
+ */
+fn process_mlock_command(conn: usize, lease_time: f64, keys: &[&str]) -> String {
+    let mut iekeys: Vec<String> = Vec::new();
+    for key in keys {
+        if !iekeys.iter().any(|k| k == key) {
+            iekeys.push(key.to_string());
+        }
+    }
+
+    let mut result = "OK".to_string();
+    {
+        let cur_time = Instant::now();
+        let exp_time = cur_time + Duration::from_millis((lease_time * 1000.0) as u64);
+        let mut iek = IEK.lock().unwrap();
+
+        let mut contended: Vec<&String> = Vec::new();
+        for iekey in &iekeys {
+            match iek.get(iekey).map(|d| &d.state) {
+                Some(IEKState::Lock(l)) => match l.owned(cur_time) {
+                    Some(h) if h.owned_by(conn, None, None) => (),
+                    None if l.queue.is_empty() => (),
+                    _ => contended.push(iekey)
+                },
+                Some(_) => return "TYPE_MISMATCH\r\n".to_string(),
+                None => ()
+            }
+        }
+
+        if !contended.is_empty() {
+            result = "CONTENDED".to_string();
+            for iekey in contended {
+                if let Some(data) = iek.get_mut(iekey) && let IEKState::Lock(l) = &mut data.state {
+                    add_waiter(&mut data.waiters, conn, None);
+                    let remaining = time_left(l.hold.iter().map(|h| h.lease_time), cur_time);
+                    l.retrying.insert((conn, None), cur_time + remaining + RETRY_GRACE);
+                }
+                result += &format!(" {}", iekey);
+            }
+        } else {
+            for iekey in &iekeys {
+                let data = iek.entry(iekey.clone())
+                              .or_insert_with(|| IEKData::new(IEKState::Lock(LockState::default())));
+                if let IEKState::Lock(l) = &mut data.state {
+                    match l.hold.as_mut() {
+                        Some(h) if h.lease_time >= cur_time && h.owned_by(conn, None, None) =>
+                            h.lease_time = exp_time,
+                        _ => {
+                            l.hold = Some(Hold {
+                                conn: Some(conn),
+                                token: None,
+                                on_disconnect: None,
+                                sub_key: None,
+                                lease_time: exp_time,
+                                fence: next_fence(),
+                                acquired: SystemTime::now(),
+                                hold_count: 1
+                            });
+                            remove_waiter(&mut data.waiters, conn, None);
+                            l.retrying.remove(&(conn, None));
+                        }
+                    }
+                    result += &format!(" {}", l.hold.as_ref().map_or(0, |h| h.fence));
+                }
+                schedule(exp_time, iekey);
+            }
+        }
+    }
+
+    for iekey in iekeys {
+        add_conn_key(conn, iekey);
+    }
+    return result + "\r\n";
+}
+
+/* Unlocks every key, answering `NOT_OWNED <key> ...` with the ones that were not released. */
+fn process_munlock_command(conn: usize, keys: &[&str]) -> String {
+    let mut failed = String::new();
+    for key in keys {
+        let r = process_unlock_command(conn, key.to_string(), None, None, None);
+        if r != "SUCCESS\r\n" && !r.starts_with("HELD ") {
+            failed += &format!(" {}", key);
+        }
+    }
+    if failed.is_empty() {
+        return "SUCCESS\r\n".to_string();
+    }
+    return format!("NOT_OWNED{}\r\n", failed);
+}
+
+fn client_ip(conn: usize) -> String {
+    return TRANSPORT.get()
+                    .and_then(|t| t.client_ip(conn))
+                    .unwrap_or_else(|| "-".to_string());
+}
+
+fn log(msg: String) {
+    if let Some(transport) = TRANSPORT.get() {
+        transport.log(&msg);
+    }
+}
+
+/* Checks the admin ACL, logging the attempt either way. */
+fn admin_allowed(conn: usize, command: &str) -> bool {
+    let ip = client_ip(conn);
+    let allowed = ADMINS.get().is_some_and(|a| a.contains(&ip));
+    log(format!("{} by connection {} ({}) {}", command, conn, ip,
+                if allowed { "allowed" } else { "denied" }));
+    return allowed;
+}
+
+/* Takes every live hold of the key away, telling each holder `LOCK_REVOKED <key>`. */
+fn process_unlock_force_command(conn: usize, iekey: String) -> String {
+    if !admin_allowed(conn, &format!("unlock_force {}", iekey)) {
+        return "NOT_ALLOWED\r\n".to_string();
+    }
+
+    let mut revoked: Vec<(usize, Option<i32>)> = Vec::new();
+    {
+        let mut iek = IEK.lock().unwrap();
+        let data = match iek.get_mut(&iekey) {
+            Some(data) => data,
+            None => return "NOT_FOUND\r\n".to_string()
+        };
+        let cur_time = Instant::now();
+        if matches!(data.state, IEKState::PubSub) {
+            return "TYPE_MISMATCH\r\n".to_string();
+        }
+        if data.state.is_free(cur_time) {
+            return "NOT_FOUND\r\n".to_string();
+        }
+
+        match &mut data.state {
+            IEKState::Lock(l) => {
+                if let Some(h) = l.hold.take() && h.lease_time >= cur_time && let Some(c) = h.conn {
+                    revoked.push((c, h.sub_key));
+                }
+            },
+            IEKState::RWLock(rw) => {
+                if let Some((writer, h)) = rw.writer.take() && h.lease_time >= cur_time {
+                    revoked.push(writer);
+                }
+                for (reader, h) in rw.readers.drain() {
+                    if h.lease_time >= cur_time {
+                        revoked.push(reader);
+                    }
+                }
+            },
+            IEKState::Semaphore(s) => {
+                for (c, (h, _)) in s.holders.drain() {
+                    if h.lease_time >= cur_time {
+                        revoked.push((c, None));
+                    }
+                }
+            },
+            IEKState::Election(e) => {
+                if let Some(leader) = e.leader.take() {
+                    revoked.push((leader.conn, None));
+                }
+            },
+            IEKState::Flight(f) => revoked.push((f.leader, None)),
+            _ => ()
+        }
+
+        send(notices("LOCK_REVOKED", &iekey, &revoked));
+        if after_release(&iekey, data, true) {
+            iek.remove(&iekey);
+        }
+    }
+
+    let mut holders: Vec<String> = revoked.iter().map(|(c, _)| c.to_string()).collect();
+    if holders.is_empty() {
+        /* A token-bound lock no connection presents right now. */
+        holders.push("-".to_string());
+    }
+    log(format!("unlock_force {} revoked from connection(s) {}", iekey, holders.join(" ")));
+    return "SUCCESS\r\n".to_string();
+}
+
+/*
+ * Hands a held exclusive lock to another live connection under a new fencing
+ * token, keeping the remaining lease. The former owner is told `LOCK_REVOKED`,
+ * the new one `LOCKED <key> <token>`, or `OK <token>` if it was parked on it.
+ * Its `disconnect=` policy goes along; token-bound locks are refused.
+ */
+fn process_lock_transfer_command(conn: usize, iekey: String, target: usize) -> String {
+    if !admin_allowed(conn, &format!("lock_transfer {} {}", iekey, target)) {
+        return "NOT_ALLOWED\r\n".to_string();
+    }
+    /* Only connections still known here can be written to. */
+    if !CONN.lock().unwrap().contains_key(&target) {
+        return "NOT_FOUND_CONN\r\n".to_string();
+    }
+
+    let fence: u64;
+    let former: Option<usize>;
+    {
+        let mut iek = IEK.lock().unwrap();
+        let IEKData { state, waiters } = match iek.get_mut(&iekey) {
+            Some(data) => data,
+            None => return "NOT_FOUND\r\n".to_string()
+        };
+        let l = match state {
+            IEKState::Lock(l) => l,
+            _ => return "TYPE_MISMATCH\r\n".to_string()
+        };
+        let h = match l.hold.as_mut() {
+            Some(h) if h.lease_time >= Instant::now() => h,
+            _ => return "NOT_FOUND\r\n".to_string()
+        };
+        /* The token owns such a lock, not a connection: it cannot be handed over. */
+        if h.token.is_some() {
+            return "TOKEN_BOUND\r\n".to_string();
+        }
+        former = h.conn;
+
+        if let Some(owner) = former {
+            send(notices("LOCK_REVOKED", &iekey, &[(owner, h.sub_key)]));
+        }
+        fence = next_fence();
+        *h = Hold {
+            conn: Some(target),
+            token: None,
+            on_disconnect: h.on_disconnect,
+            sub_key: None,
+            lease_time: h.lease_time,
+            fence,
+            acquired: SystemTime::now(),
+            hold_count: 1
+        };
+        remove_waiter(waiters, target, None);
+
+        let queued = l.queue.iter().position(|w| w.conn == target && w.sub_key.is_none());
+        match queued.and_then(|i| l.queue.remove(i)) {
+            Some(w) if w.deadline.is_some() => reply(target, format!("OK {}\r\n", fence)),
+            _ => send(vec![(target, format!("LOCKED {} {}\r\n", iekey, fence))])
+        }
+        let former_stays = former.is_some_and(|f| waiters.contains_key(&f) ||
+                                                  l.queue.iter().any(|w| w.conn == f));
+        rebind_conn_key(&iekey, former, target, former_stays);
+    }
+
+    log(format!("lock_transfer {} moved from connection {} to {}", iekey,
+                former.map_or("-".to_string(), |c| c.to_string()), target));
+    return format!("OK {}\r\n", fence);
+}
+
+fn type_name(iek_type: &IEKType) -> &'static str {
+    match iek_type {
+        IEKType::PubSub => return "pubsub",
+        IEKType::Lock => return "lock",
+        IEKType::RWLock => return "rwlock",
+        IEKType::Semaphore => return "semaphore",
+        IEKType::Latch => return "latch",
+        IEKType::Barrier => return "barrier",
+        IEKType::Election => return "election",
+        IEKType::RateLimit => return "ratelimit",
+        IEKType::Condition => return "condition",
+        IEKType::Sequence => return "sequence",
+        IEKType::Flight => return "flight"
+    }
+}
+
+/* Answers every follower of a flight whose leader went away with FLIGHT_FAILED. */
+fn fail_flight(flight: &mut FlightState) {
+    for w in flight.followers.drain(..) {
+        reply(w.conn, "FLIGHT_FAILED\r\n".to_string());
+    }
+}
+
+/*
+ * The first caller of a key becomes its leader and is answered `LEADER`; it is
+ * to compute the result and hand it over with flight_done within `lease`.
+ * Callers arriving meanwhile are parked until then and all answered with the
+ * same result, or with FLIGHT_FAILED if the leader goes away or its lease runs
+ * out; one whose own `lease` runs out first is answered TIMEOUT. Nothing is
+ * kept afterwards: the next caller leads a new flight.
+ */
+fn process_flight_command(conn: usize, iekey: String, lease_time: f64) -> String {
+    let result: String;
+    {
+        let cur_time = Instant::now();
+        let exp_time = cur_time + Duration::from_millis((lease_time * 1000.0) as u64);
+        let mut iek = IEK.lock().unwrap();
+        let data = iek.entry(iekey.clone()).or_insert_with(|| IEKData::new(IEKState::Flight(FlightState {
+            leader: conn,
+            lease_time: exp_time,
+            fence: next_fence(),
+            acquired: SystemTime::now(),
+            followers: VecDeque::new()
+        })));
+        let f = match &mut data.state {
+            IEKState::Flight(f) => f,
+            _ => return "TYPE_MISMATCH\r\n".to_string()
+        };
+
+        if f.leader == conn {
+            f.lease_time = exp_time;
+            schedule(exp_time, &iekey);
+            result = "LEADER\r\n".to_string();
+        } else {
+            /* Parked: answered by flight_done, or by on_timer once its own lease runs out. */
+            f.followers.push_back(Parked { conn, deadline: exp_time });
+            schedule(exp_time, &iekey);
+            result = String::new();
+        }
+    }
+
+    add_conn_key(conn, iekey);
+    return result;
+}
+
+/*
+ * Ends the flight led by the connection, answering every follower with
+ * `RESULT <bytes>` and the data, and the leader with `WOKEN <followers>`.
+ */
+fn process_flight_done_command(conn: usize, iekey: String, data: &str) -> String {
+    let followers: VecDeque<Parked>;
+    {
+        let mut iek = IEK.lock().unwrap();
+        match iek.get_mut(&iekey).map(|d| &mut d.state) {
+            Some(IEKState::Flight(f)) if f.leader == conn => followers = std::mem::take(&mut f.followers),
+            Some(IEKState::Flight(_)) => return "NOT_OWNED\r\n".to_string(),
+            Some(_) => return "TYPE_MISMATCH\r\n".to_string(),
+            None => return "NOT_FOUND\r\n".to_string()
+        }
+        iek.remove(&iekey);
+    }
+
+    let msg = format!("RESULT {}\r\n{}\r\n", data.len(), data);
+    for w in &followers {
+        reply(w.conn, msg.clone());
+    }
+    let mut conns = CONN.lock().unwrap();
+    if let Some(s) = conns.get_mut(&conn) {
+        s.remove(&iekey);
+    }
+    return format!("WOKEN {}\r\n", followers.len());
+}
+
+/*
+ * Reserves the next `count` ids of the sequence, answering `RANGE <first> <last>`.
+ * Ids start at 1. With a sequence file, the ceiling is persisted before any id
+ * past it is handed out, so ids never go backwards across restarts.
+ */
+fn process_seq_next_command(name: String, count: u64) -> String {
+    let first: u64;
+    let end: u64;
+    let generation: u64;
+    {
+        let mut iek = IEK.lock().unwrap();
+        let data = iek.entry(name).or_insert_with(|| {
+            IEKData::new(IEKState::Sequence(Sequence { next: 1, ceiling: 1, generation: 0 }))
+        });
+        let seq = match &mut data.state {
+            IEKState::Sequence(seq) => seq,
+            _ => return "TYPE_MISMATCH\r\n".to_string()
+        };
+
+        first = seq.next;
+        end = match first.checked_add(count) {
+            Some(end) => end,
+            None => return "SERVER_ERROR sequence exhausted\r\n".to_string()
+        };
+        seq.next = end;
+        if end > seq.ceiling {
+            seq.ceiling = end.saturating_add(SEQ_BLOCK);
+            seq.generation = SEQ_GEN.fetch_add(1, Ordering::Relaxed) + 1;
+        }
+        generation = seq.generation;
+    }
+
+    /* Ids that could not be persisted are skipped, never handed out again. */
+    if !save_sequences(generation) {
+        return "SERVER_ERROR failed to persist sequence\r\n".to_string();
+    }
+    return format!("RANGE {} {}\r\n", first, end - 1);
+}
+
+/* Parks the connection on the key until notify wakes it, or answers TIMEOUT after `timeout` seconds. */
+fn process_wait_command(conn: usize, iekey: String, timeout: f64) -> String {
+    {
+        let deadline = Instant::now() + Duration::from_millis((timeout * 1000.0) as u64);
+        let mut iek = IEK.lock().unwrap();
+        let data = iek.entry(iekey.clone()).or_insert_with(|| IEKData::new(IEKState::Condition(VecDeque::new())));
+        let queue = match &mut data.state {
+            IEKState::Condition(queue) => queue,
+            _ => return "TYPE_MISMATCH\r\n".to_string()
+        };
+        queue.push_back(Parked { conn, deadline });
+        schedule(deadline, &iekey);
+    }
+
+    add_conn_key(conn, iekey);
+    return String::new();
+}
+
+/*
+ * Wakes the `n` longest waiting connections, or all of them with None, each
+ * answered `NOTIFIED [payload]`. Tells the caller how many were woken.
+ */
+fn process_notify_command(iekey: String, n: Option<usize>, payload: Option<&str>) -> String {
+    let woken: Vec<Parked>;
+    {
+        let mut iek = IEK.lock().unwrap();
+        let queue = match iek.get_mut(&iekey).map(|d| &mut d.state) {
+            Some(IEKState::Condition(queue)) => queue,
+            Some(_) => return "TYPE_MISMATCH\r\n".to_string(),
+            None => return "WOKEN 0\r\n".to_string()
+        };
+        let n = n.unwrap_or(queue.len()).min(queue.len());
+        woken = queue.drain(..n).collect();
+        if queue.is_empty() {
+            iek.remove(&iekey);
+        }
+    }
+
+    let msg = match payload {
+        Some(p) => format!("NOTIFIED {}\r\n", p),
+        None => "NOTIFIED\r\n".to_string()
+    };
+    for w in &woken {
+        reply(w.conn, msg.clone());
+    }
+    return format!("WOKEN {}\r\n", woken.len());
+}
+
+/*
+ * Creates a latch that opens once counted down `count` times. An open latch
+ * may be created again with a new count; a closed one answers EXISTS.
+ */
+fn process_latch_create_command(iekey: String, count: u32) -> String {
+    let mut iek = IEK.lock().unwrap();
+    let data = iek.entry(iekey).or_insert_with(|| IEKData::new(IEKState::Latch(LatchState {
+        left: 0,
+        open_until: None
+    })));
+    let l = match &mut data.state {
+        IEKState::Latch(l) => l,
+        _ => return "TYPE_MISMATCH\r\n".to_string()
+    };
+    if l.left > 0 {
+        return "EXISTS\r\n".to_string();
+    }
+    l.left = count;
+    l.open_until = None;
+    return "CREATED\r\n".to_string();
+}
+
+/*
+ * Counts the latch down by `n`, answering `COUNT <left>`; at zero waiters are
+ * told `LATCH_OPEN <key>`, and the latch is dropped LATCH_OPEN_TTL later.
+ */
+fn process_latch_countdown_command(iekey: String, n: u32) -> String {
+    let mut iek = IEK.lock().unwrap();
+    let IEKData { state, waiters } = match iek.get_mut(&iekey) {
+        Some(data) => data,
+        None => return "NOT_FOUND\r\n".to_string()
+    };
+    let l = match state {
+        IEKState::Latch(l) => l,
+        _ => return "TYPE_MISMATCH\r\n".to_string()
+    };
+    if l.left == 0 {
+        return "COUNT 0\r\n".to_string();
+    }
+
+    l.left = l.left.saturating_sub(n);
+    if l.left == 0 {
+        do_publish(waiters, format!("LATCH_OPEN {}", iekey));
+        waiters.clear();
+        let open_until = Instant::now() + LATCH_OPEN_TTL;
+        l.open_until = Some(open_until);
+        schedule(open_until, &iekey);
+    }
+    return format!("COUNT {}\r\n", l.left);
+}
+
+/* `OPEN` if the latch is open already, else `WAITING <count>` and `LATCH_OPEN <key>` once it opens. */
+fn process_latch_wait_command(conn: usize, iekey: String) -> String {
+    let result: String;
+    {
+        let mut iek = IEK.lock().unwrap();
+        let IEKData { state, waiters } = match iek.get_mut(&iekey) {
+            Some(data) => data,
+            None => return "NOT_FOUND\r\n".to_string()
+        };
+        match state {
+            IEKState::Latch(l) if l.left == 0 => return "OPEN\r\n".to_string(),
+            IEKState::Latch(l) => {
+                add_waiter(waiters, conn, None);
+                result = format!("WAITING {}\r\n", l.left);
+            },
+            _ => return "TYPE_MISMATCH\r\n".to_string()
+        }
+    }
+
+    add_conn_key(conn, iekey);
+    return result;
+}
+
+/*
+ * Arrives at a cyclic barrier of `parties`. Until the last party arrives the
+ * reply is `WAITING <arrived>`; the last one gets `OPEN` and everyone else
+ * `BARRIER_OPEN <key>`, after which the barrier starts over empty.
+ */
+fn process_barrier_wait_command(conn: usize, iekey: String, parties: u32) -> String {
+    let result: String;
+    {
+        let mut iek = IEK.lock().unwrap();
+        let IEKData { state, waiters } = iek.entry(iekey.clone())
+                                            .or_insert_with(|| IEKData::new(IEKState::Barrier(parties)));
+        match state {
+            IEKState::Barrier(n) if *n == parties => (),
+            IEKState::Barrier(_) => return "MAX_MISMATCH\r\n".to_string(),
+            _ => return "TYPE_MISMATCH\r\n".to_string()
+        }
+
+        add_waiter(waiters, conn, None);
+        if waiters.len() < parties as usize {
+            result = format!("WAITING {}\r\n", waiters.len());
+        } else {
+            remove_waiter(waiters, conn, None);
+            do_publish(waiters, format!("BARRIER_OPEN {}", iekey));
+            iek.remove(&iekey);
+            return "OPEN\r\n".to_string();
+        }
+    }
+
+    add_conn_key(conn, iekey);
+    return result;
+}
+
+/*
+ * Takes `cost` tokens out of the bucket of `capacity` tokens, refilled at
+ * `refill_per_sec`: `ALLOWED <tokens left>`, or `LIMITED <secs until enough>`
+ * without taking any. Other settings than the bucket has are taken over, so
+ * quotas can be changed on the fly. A bucket that has filled up again is
+ * dropped by its timer.
+ */
+fn process_ratelimit_command(iekey: String, capacity: u32, refill_per_sec: f64, cost: u32) -> String {
+    let cur_time = Instant::now();
+    let mut iek = IEK.lock().unwrap();
+    let data = match iek.entry(iekey.clone()) {
+        Entry::Vacant(e) => {
+            /* The timer keeps coming back until the bucket is full again. */
+            schedule(cur_time, &iekey);
+            e.insert(IEKData::new(IEKState::RateLimit(Bucket {
+                capacity: capacity as f64,
+                refill_per_sec,
+                tokens: capacity as f64,
+                updated: cur_time
+            })))
+        },
+        Entry::Occupied(e) => e.into_mut()
+    };
+    let b = match &mut data.state {
+        IEKState::RateLimit(b) => b,
+        _ => return "TYPE_MISMATCH\r\n".to_string()
+    };
+
+    b.refill(cur_time);
+    b.capacity = capacity as f64;
+    b.refill_per_sec = refill_per_sec;
+    b.tokens = b.tokens.min(b.capacity);
+    if b.tokens >= cost as f64 {
+        b.tokens -= cost as f64;
+        return format!("ALLOWED {}\r\n", b.tokens.floor() as u64);
+    }
+    let retry_after = (cost as f64 - b.tokens) / refill_per_sec;
+    return format!("LIMITED {:.3}\r\n", retry_after);
+}
+
+fn next_term(group: &str) -> u64 {
+    let mut terms = TERMS.lock().unwrap();
+    let term = terms.entry(group.to_string()).or_default();
+    *term += 1;
+    return *term;
+}
+
+/*
+ * Makes the longest waiting follower the leader of the group under the group's
+ * next term and tells every candidate but `caller`, who is answered instead,
+ * `LEADER <group> <id> <term>`. Returns false when no follower is left.
+ */
+fn elect_next(group: &str, election: &mut ElectionState, caller: Option<usize>) -> bool {
+    let c = match election.followers.pop_front() {
+        Some(c) => c,
+        None => return false
+    };
+    let leader = Leader {
+        conn: c.conn,
+        id: c.id,
+        lease_time: Instant::now() + c.lease,
+        term: next_term(group),
+        acquired: SystemTime::now()
+    };
+    schedule(leader.lease_time, group);
+
+    let msg = format!("LEADER {} {} {}\r\n", group, leader.id, leader.term);
+    let mut conns: HashSet<usize> = election.followers.iter().map(|f| f.conn).collect();
+    conns.insert(leader.conn);
+    if let Some(caller) = caller {
+        conns.remove(&caller);
+    }
+    send(conns.into_iter().map(|c| (c, msg.clone())).collect());
+    election.leader = Some(leader);
+    return true;
+}
+
+/*
+ * Joins the election of `group` as `id`, or renews the lease if `id` leads it.
+ * Answers `ELECTED <term>` to the leader and `FOLLOWER <leader id> <term>` to
+ * the others, who take over in arrival order once the leader's lease runs out
+ * or its connection goes away.
+ */
+fn process_elect_command(conn: usize, group: String, id: &str, lease_time: f64) -> String {
+    let result: String;
+    {
+        let cur_time = Instant::now();
+        let lease = Duration::from_millis((lease_time * 1000.0) as u64);
+        let mut iek = IEK.lock().unwrap();
+        let data = iek.entry(group.clone()).or_insert_with(|| IEKData::new(IEKState::Election(ElectionState {
+            leader: None,
+            followers: VecDeque::new()
+        })));
+        let e = match &mut data.state {
+            IEKState::Election(e) => e,
+            _ => return "TYPE_MISMATCH\r\n".to_string()
+        };
+
+        if let Some(l) = e.leader.as_mut() && l.lease_time >= cur_time && l.id == id {
+            l.conn = conn;
+            l.lease_time = cur_time + lease;
+            schedule(l.lease_time, &group);
+        } else {
+            match e.followers.iter_mut().find(|c| c.id == id) {
+                Some(c) => {
+                    c.conn = conn;
+                    c.lease = lease;
+                },
+                None => e.followers.push_back(Candidate { conn, id: id.to_string(), lease })
+            }
+            /* The leader's lease ran out before its timer fired. */
+            if e.leader.as_ref().is_none_or(|l| l.lease_time < cur_time) {
+                if let Some(former) = e.leader.take() {
+                    send(notices("LEASE_EXPIRED", &group, &[(former.conn, None)]));
+                }
+                elect_next(&group, e, Some(conn));
+            }
+        }
+
+        match &e.leader {
+            Some(l) if l.id == id => result = format!("ELECTED {}\r\n", l.term),
+            Some(l) => result = format!("FOLLOWER {} {}\r\n", l.id, l.term),
+            None => result = "FOLLOWER - 0\r\n".to_string()
+        }
+    }
+
+    add_conn_key(conn, group);
+    return result;
+}
+
+fn fmt_sub_key(sub_key: Option<i32>) -> String {
+    return sub_key.map_or("-".to_string(), |s| s.to_string());
+}
+
+fn fmt_secs(d: Duration) -> String {
+    return format!("{:.3}", d.as_millis() as f64 / 1000.0);
+}
+
+fn fmt_time(t: SystemTime) -> String {
+    return fmt_secs(t.duration_since(UNIX_EPOCH).unwrap_or_default());
+}
+
+/* `*` matches any run of characters and `?` any single one. */
+fn glob_match(pattern: &[u8], s: &[u8]) -> bool {
+    match (pattern.first(), s.first()) {
+        (None, None) => return true,
+        (Some(b'*'), _) =>
+            return glob_match(&pattern[1..], s) || (!s.is_empty() && glob_match(pattern, &s[1..])),
+        (Some(b'?'), Some(_)) => return glob_match(&pattern[1..], &s[1..]),
+        (Some(p), Some(c)) if p == c => return glob_match(&pattern[1..], &s[1..]),
+        _ => return false
+    }
+}
+
+/* `OWNER <conn> <ip> <sub_key> <remaining lease> <fence> <acquired>`, `-` for a token-bound lock nobody presents. */
+fn owner_line(conn: Option<usize>, sub_key: Option<i32>, lease_time: Instant, fence: u64,
+              acquired: SystemTime, now: Instant) -> String {
+    let (conn, ip) = match conn {
+        Some(conn) => (conn.to_string(), client_ip(conn)),
+        None => ("-".to_string(), "-".to_string())
+    };
+    return format!("OWNER {} {} {} {} {} {}\r\n", conn, ip, fmt_sub_key(sub_key),
+                   fmt_secs(lease_time - now), fence, fmt_time(acquired));
+}
+
+fn holder_line(conn: usize, sub_key: Option<i32>, h: &Holder, permits: u32, now: Instant) -> String {
+    return format!("HOLDER {} {} {} {} {} {} {}\r\n", conn, client_ip(conn), fmt_sub_key(sub_key),
+                   fmt_secs(h.lease_time - now), h.fence, fmt_time(h.acquired), permits);
+}
+
+fn waiter_line(conn: usize, sub_key: Option<i32>, kind: &str) -> String {
+    return format!("WAITER {} {} {}{}\r\n", conn, client_ip(conn), fmt_sub_key(sub_key), kind);
+}
+
+/*
+ * Everything known about a lock key, one line per holder and waiter:
+ *   LOCKINFO <key> <type> <fence of the current holder or 0>
+ *   OWNER <conn> <ip> <sub_key> <remaining lease> <fence> <acquired at, unix secs>
+ *   HOLDER <conn> <ip> <sub_key> <remaining lease> <fence> <acquired at> <permits>
+ *   WAITER <conn> <ip> <sub_key> [queued|parked|writer]
+ *   END
+ */
+fn process_lockinfo_command(iekey: String) -> String {
+    let iek = IEK.lock().unwrap();
+    let data = match iek.get(&iekey) {
+        Some(data) if matches!(data.state, IEKState::PubSub) => return "TYPE_MISMATCH\r\n".to_string(),
+        Some(data) => data,
+        None => return "NOT_FOUND\r\n".to_string()
+    };
+
+    let cur_time = Instant::now();
+    let mut fence = 0;
+    let mut lines = String::new();
+    match &data.state {
+        IEKState::Lock(l) => {
+            if let Some(h) = l.owned(cur_time) {
+                fence = h.fence;
+                lines += &owner_line(h.conn, h.sub_key, h.lease_time, h.fence, h.acquired, cur_time);
+            }
+            for w in &l.queue {
+                let kind = if w.deadline.is_some() { " parked" } else { " queued" };
+                lines += &waiter_line(w.conn, w.sub_key, kind);
+            }
+        },
+        IEKState::RWLock(rw) => {
+            if let Some(((conn, sub_key), h)) = &rw.writer && h.lease_time >= cur_time {
+                fence = h.fence;
+                lines += &owner_line(Some(*conn), *sub_key, h.lease_time, h.fence, h.acquired, cur_time);
+            }
+            for ((conn, sub_key), h) in &rw.readers {
+                if h.lease_time >= cur_time {
+                    lines += &holder_line(*conn, *sub_key, h, 1, cur_time);
+                }
+            }
+        },
+        IEKState::Semaphore(s) => {
+            for (conn, (h, permits)) in &s.holders {
+                if h.lease_time >= cur_time {
+                    lines += &holder_line(*conn, None, h, *permits, cur_time);
+                }
+            }
+        },
+        IEKState::Election(e) => {
+            if let Some(l) = &e.leader && l.lease_time >= cur_time {
+                fence = l.term;
+                lines += &owner_line(Some(l.conn), None, l.lease_time, l.term, l.acquired, cur_time);
+            }
+            for c in &e.followers {
+                lines += &waiter_line(c.conn, None, " queued");
+            }
+        },
+        IEKState::Flight(f) => {
+            if f.lease_time >= cur_time {
+                fence = f.fence;
+                lines += &owner_line(Some(f.leader), None, f.lease_time, f.fence, f.acquired, cur_time);
+            }
+            for w in &f.followers {
+                lines += &waiter_line(w.conn, None, " parked");
+            }
+        },
+        IEKState::Condition(queue) => {
+            for w in queue {
+                lines += &waiter_line(w.conn, None, " parked");
+            }
+        },
+        _ => ()
+    }
+    for (conn, s) in &data.waiters {
+        for sub_key in s {
+            let writer = matches!(&data.state, IEKState::RWLock(rw)
+                                  if rw.writer_waiters.contains_key(&(*conn, *sub_key)));
+            lines += &waiter_line(*conn, *sub_key, if writer { " writer" } else { "" });
+        }
+    }
+
+    let mut result = format!("LOCKINFO {} {} {}\r\n", iekey, type_name(&data.state.iek_type()), fence);
+    result += &lines;
+    result += "END\r\n";
+    return result;
+}
+
+/*
+ * Lock keys matching `pattern`, sorted:
+ *   LOCKS <count>
+ *   <key> <type> <holders> <waiters> <remaining lease>
+ *   END
+ */
+fn process_locks_command(pattern: Option<&str>) -> String {
+    let iek = IEK.lock().unwrap();
+    let cur_time = Instant::now();
+    let mut lines: Vec<String> = Vec::new();
+    for (iekey, data) in iek.iter() {
+        if !matches!(data.state, IEKState::Lock(_) | IEKState::RWLock(_) | IEKState::Semaphore(_)) ||
+            pattern.is_some_and(|p| !glob_match(p.as_bytes(), iekey.as_bytes())) {
+            continue;
+        }
+        let holders = data.state.live_leases(cur_time).len();
+        let waiters = data.state.queued() + data.waiters.values().map(|s| s.len()).sum::<usize>();
+        lines.push(format!("{} {} {} {} {}\r\n", iekey, type_name(&data.state.iek_type()), holders, waiters,
+                           fmt_secs(holders_remaining(&data.state, cur_time))));
+    }
+    lines.sort();
+
+    let mut result = format!("LOCKS {}\r\n", lines.len());
+    for line in lines {
+        result += &line;
+    }
+    result += "END\r\n";
+    return result;
+}
+
+/* Trailing `name=value` or bare flag tokens a command understands, e.g. `fence=12`. */
+fn command_options(cmd_type: CMDType) -> &'static [&'static str] {
+    match cmd_type {
+        CMDType::Lock => &["reentrant", "fair", "wait", "owner", "disconnect"],
+        CMDType::Unlock => &["fence", "owner"],
+        CMDType::Relock => &["owner"],
+        _ => &[]
+    }
+}
+
+/* Options that may also be written as two tokens, `name <value>`. */
+const VALUED_OPTIONS: &[&str] = &["wait"];
+
+fn split_options<'a>(argv: &[&'a str], names: &[&str]) -> (Vec<&'a str>, HashMap<&'a str, &'a str>) {
+    let mut args: Vec<&str> = Vec::new();
+    let mut options: HashMap<&str, &str> = HashMap::new();
+
+    let mut i = 0;
+    while i < argv.len() {
+        let arg = argv[i];
+        match arg.split_once('=') {
+            Some((name, value)) if i >= 2 && names.contains(&name) => {
+                options.insert(name, value);
+            },
+            None if i >= 2 && names.contains(&arg) && VALUED_OPTIONS.contains(&arg) && i + 1 < argv.len() => {
+                options.insert(arg, argv[i + 1]);
+                i += 1;
+            },
+            None if i >= 2 && names.contains(&arg) => {
+                options.insert(arg, "");
+            },
+            _ => args.push(arg)
+        }
+        i += 1;
+    }
+    return (args, options);
+}
+
+/* Decides which command the tokens of a request line map to, or Unknown if none accepts them. */
+pub fn parse_command(argv: &[&str]) -> CMDType {
+    let cmd_type = match argv.first() {
+        Some(&"lock") => CMDType::Lock,
+        Some(&"unlock") => CMDType::Unlock,
+        Some(&"rlock") => CMDType::RLock,
+        Some(&"wlock") => CMDType::WLock,
+        Some(&"sem_acquire") => CMDType::SemAcquire,
+        Some(&"sem_release") => CMDType::SemRelease,
+        Some(&"relock") => CMDType::Relock,
+        Some(&"lockttl") => CMDType::LockTTL,
+        Some(&"lockinfo") => CMDType::LockInfo,
+        Some(&"locks") => CMDType::Locks,
+        Some(&"mlock") => CMDType::MLock,
+        Some(&"munlock") => CMDType::MUnlock,
+        Some(&"unlock_force") => CMDType::UnlockForce,
+        Some(&"lock_transfer") => CMDType::LockTransfer,
+        Some(&"latch_create") => CMDType::LatchCreate,
+        Some(&"latch_countdown") => CMDType::LatchCountdown,
+        Some(&"latch_wait") => CMDType::LatchWait,
+        Some(&"barrier_wait") => CMDType::BarrierWait,
+        Some(&"elect") => CMDType::Elect,
+        Some(&"ratelimit") => CMDType::RateLimit,
+        Some(&"wait") => CMDType::Wait,
+        Some(&"notify") => CMDType::Notify,
+        Some(&"seq_next") => CMDType::SeqNext,
+        Some(&"flight") => CMDType::Flight,
+        Some(&"flight_done") => CMDType::FlightDone,
+        Some(&"publish") => CMDType::Publish,
+        Some(&"subscribe") => CMDType::Subscribe,
+        Some(&"unsubscribe") => CMDType::Unsubscribe,
+        _ => CMDType::Unknown
+    };
+
+    let argc = split_options(argv, command_options(cmd_type)).0.len();
+    let accepted = match cmd_type {
+        CMDType::Lock | CMDType::RLock | CMDType::WLock | CMDType::Relock => (3..=4).contains(&argc),
+        CMDType::LockTTL | CMDType::LockInfo => argc == 2,
+        CMDType::Locks => (1..=2).contains(&argc),
+        CMDType::MLock => argc >= 3,
+        CMDType::MUnlock => argc >= 2,
+        CMDType::UnlockForce => argc == 2,
+        CMDType::LockTransfer => argc == 3,
+        CMDType::LatchCreate | CMDType::BarrierWait => argc == 3,
+        CMDType::LatchCountdown => (2..=3).contains(&argc),
+        CMDType::LatchWait => argc == 2,
+        CMDType::Elect => argc == 4,
+        CMDType::RateLimit => (4..=5).contains(&argc),
+        CMDType::Wait => argc == 3,
+        CMDType::Notify => (2..=4).contains(&argc),
+        CMDType::SeqNext => (2..=3).contains(&argc),
+        CMDType::Flight => argc == 3,
+        CMDType::FlightDone => argc == 3 && argv[2].parse::<usize>().is_ok(),
+        CMDType::Unlock | CMDType::SemRelease => (2..=3).contains(&argc),
+        CMDType::SemAcquire => argc == 5,
+        CMDType::Publish => argc == 3,
+        CMDType::Subscribe | CMDType::Unsubscribe => argc >= 2,
+        CMDType::Unknown => false
+    };
+    if accepted {
+        return cmd_type;
+    }
+    return CMDType::Unknown;
+}
+
+/*
+ * Bytes of data following the request line of a command, not counting the
+ * trailing "\r\n", or None if the command carries none. The host reads them
+ * and runs the command with process_data_command instead of process_command.
+ * A block over MAX_DATA_LENGTH is not to be read but skipped where the host
+ * can, and process_command refuses it.
+ */
+pub fn data_length(cmd_type: CMDType, argv: &[&str]) -> Option<usize> {
+    match cmd_type {
+        CMDType::FlightDone =>
+            return argv.get(2).and_then(|n| n.parse::<usize>().ok()),
+        _ => return None
+    }
+}
+
+/*
+ * `data` is the block announced by data_length, trailing "\r\n" included.
+ * Results are passed on as text, so the data must be valid UTF-8.
+ */
+pub fn process_data_command(conn: usize, cmd_type: CMDType, argv: &[&str], data: &[u8]) -> String {
+    let data = match data.strip_suffix(b"\r\n").map(std::str::from_utf8) {
+        Some(Ok(data)) => data,
+        _ => return "CLIENT_ERROR bad data chunk\r\n".to_string()
+    };
+    match cmd_type {
+        CMDType::FlightDone if argv.len() == 3 =>
+            return process_flight_done_command(conn, argv[1].to_string(), data),
+        _ => return "ERROR unknown command\r\n".to_string()
+    }
+}
+
+/* An empty reply means the command is parked and answered later through Transport::reply. */
+pub fn process_command(conn: usize, cmd_type: CMDType, argv: &[&str]) -> String {
+    let (argv, options) = split_options(argv, command_options(cmd_type));
+    let argc = argv.len();
+    let mut cur_token: usize = 1;
+    let mut result = "ERROR unknown command\r\n".to_string();
+    match cmd_type {
+        CMDType::Locks => return process_locks_command(argv.get(1).copied()),
+        CMDType::MLock if argc >= 3 => {
+            match argv[1].parse::<f64>() {
+                Ok(num) => return process_mlock_command(conn, num, &argv[2..]),
+                Err(_e) => return "CLIENT_ERROR bad command line format\r\n".to_string()
+            }
+        },
+        CMDType::MUnlock if argc >= 2 => return process_munlock_command(conn, &argv[1..]),
+        _ => ()
+    }
+    if cmd_type == CMDType::Unknown || argc < 2 {
+        return result;
+    }
+    let mut iekey = argv[cur_token].to_string();
+    cur_token += 1;
+
+    let mut sub_key: Option<i32> = None;
+    let mut is_success = true;
+    let is_lock = matches!(cmd_type, CMDType::Lock | CMDType::RLock | CMDType::WLock | CMDType::Relock);
+    if (is_lock && (argc - cur_token) == 2) ||
+       (cmd_type == CMDType::Unlock && (argc - cur_token) == 1) {
+        let cvt_sub_key: Result<i32, _> = argv[cur_token].parse();
+        cur_token += 1;
+        match cvt_sub_key {
+            Ok(num) => sub_key = Some(num),
+            Err(_e) => {
+                is_success = false;
+                result = "CLIENT_ERROR bad command line format\r\n".to_string();
+            }
+        }
+    }
+
+    if is_success {
+        match cmd_type {
+            CMDType::Lock => {
+                let lease_time: Result<f64, _> = argv[cur_token].parse();
+                // cur_token += 1;
+                let wait = options.get("wait").map(|w| w.parse::<f64>()).transpose();
+                let token = options.get("owner").copied();
+                let on_disconnect = options.get("disconnect").map(|p| parse_disconnect_policy(p).ok_or(()))
+                                                              .transpose();
+                match (lease_time, wait, on_disconnect) {
+                    (Ok(num), Ok(wait), Ok(on_disconnect)) if wait.is_none_or(|w| w >= 0.0) && token != Some("") =>
+                        result = process_lock_command(conn, iekey, sub_key, num,
+                                                      options.contains_key("reentrant"),
+                                                      options.contains_key("fair"), wait, token,
+                                                      on_disconnect),
+                    _ =>
+                        result = "CLIENT_ERROR bad command line format\r\n".to_string(),
+                }
+            },
+            CMDType::RLock | CMDType::WLock => {
+                match argv[cur_token].parse::<f64>() {
+                    Ok(num) if cmd_type == CMDType::RLock =>
+                        result = process_rlock_command(conn, iekey, sub_key, num),
+                    Ok(num) =>
+                        result = process_wlock_command(conn, iekey, sub_key, num),
+                    Err(_e) =>
+                        result = "CLIENT_ERROR bad command line format\r\n".to_string(),
+                }
+            },
+            CMDType::Relock => {
+                let token = options.get("owner").copied();
+                match argv[cur_token].parse::<f64>() {
+                    Ok(num) if token != Some("") =>
+                        result = process_relock_command(conn, iekey, sub_key, num, token),
+                    Ok(_) =>
+                        result = "CLIENT_ERROR bad command line format\r\n".to_string(),
+                    Err(_e) =>
+                        result = "CLIENT_ERROR bad command line format\r\n".to_string(),
+                }
+            },
+            CMDType::LockTTL => {
+                result = process_lockttl_command(iekey);
+            },
+            CMDType::LockInfo => {
+                result = process_lockinfo_command(iekey);
+            },
+            CMDType::UnlockForce => {
+                result = process_unlock_force_command(conn, iekey);
+            },
+            CMDType::LockTransfer => {
+                match argv[cur_token].parse::<usize>() {
+                    Ok(target) =>
+                        result = process_lock_transfer_command(conn, iekey, target),
+                    Err(_e) =>
+                        result = "CLIENT_ERROR bad command line format\r\n".to_string(),
+                }
+            },
+            CMDType::LatchCreate | CMDType::BarrierWait => {
+                match argv[cur_token].parse::<u32>() {
+                    Ok(count) if count > 0 && cmd_type == CMDType::LatchCreate =>
+                        result = process_latch_create_command(iekey, count),
+                    Ok(parties) if parties > 0 =>
+                        result = process_barrier_wait_command(conn, iekey, parties),
+                    _ =>
+                        result = "CLIENT_ERROR bad command line format\r\n".to_string(),
+                }
+            },
+            CMDType::LatchCountdown => {
+                match argv.get(cur_token).map_or(Ok(1), |n| n.parse::<u32>()) {
+                    Ok(n) if n > 0 =>
+                        result = process_latch_countdown_command(iekey, n),
+                    _ =>
+                        result = "CLIENT_ERROR bad command line format\r\n".to_string(),
+                }
+            },
+            CMDType::LatchWait => {
+                result = process_latch_wait_command(conn, iekey);
+            },
+            CMDType::Elect => {
+                match argv[cur_token + 1].parse::<f64>() {
+                    Ok(num) =>
+                        result = process_elect_command(conn, iekey, argv[cur_token], num),
+                    Err(_e) =>
+                        result = "CLIENT_ERROR bad command line format\r\n".to_string(),
+                }
+            },
+            CMDType::RateLimit => {
+                let capacity = argv[cur_token].parse::<u32>();
+                let refill = argv[cur_token + 1].parse::<f64>();
+                let cost = argv.get(cur_token + 2).map_or(Ok(1), |c| c.parse::<u32>());
+                match (capacity, refill, cost) {
+                    (Ok(capacity), Ok(refill), Ok(cost)) if capacity > 0 && refill > 0.0 && cost <= capacity =>
+                        result = process_ratelimit_command(iekey, capacity, refill, cost),
+                    _ =>
+                        result = "CLIENT_ERROR bad command line format\r\n".to_string(),
+                }
+            },
+            CMDType::Flight => {
+                match argv[cur_token].parse::<f64>() {
+                    Ok(num) =>
+                        result = process_flight_command(conn, iekey, num),
+                    Err(_e) =>
+                        result = "CLIENT_ERROR bad command line format\r\n".to_string(),
+                }
+            },
+            CMDType::FlightDone => {
+                /* Its data is handed to process_data_command unless too large to read. */
+                result = "CLIENT_ERROR data too large\r\n".to_string();
+            },
+            CMDType::SeqNext => {
+                match argv.get(cur_token).map_or(Ok(1), |c| c.parse::<u64>()) {
+                    Ok(count) if count > 0 =>
+                        result = process_seq_next_command(iekey, count),
+                    _ =>
+                        result = "CLIENT_ERROR bad command line format\r\n".to_string(),
+                }
+            },
+            CMDType::Wait => {
+                match argv[cur_token].parse::<f64>() {
+                    Ok(timeout) if timeout >= 0.0 =>
+                        result = process_wait_command(conn, iekey, timeout),
+                    _ =>
+                        result = "CLIENT_ERROR bad command line format\r\n".to_string(),
+                }
+            },
+            CMDType::Notify => {
+                /* A lone token after the key is the count if it reads as one, else the payload. */
+                let mut n: Result<Option<usize>, _> = Ok(Some(1));
+                let mut payload = argv.get(cur_token).copied();
+                if let Some(arg) = payload && (arg == "all" || arg.parse::<usize>().is_ok() || argc == 4) {
+                    n = if arg == "all" { Ok(None) } else { arg.parse::<usize>().map(Some) };
+                    payload = argv.get(cur_token + 1).copied();
+                }
+                match n {
+                    Ok(n) =>
+                        result = process_notify_command(iekey, n, payload),
+                    Err(_e) =>
+                        result = "CLIENT_ERROR bad command line format\r\n".to_string(),
+                }
+            },
+            CMDType::SemAcquire => {
+                let permits = argv[cur_token].parse::<u32>();
+                let max = argv[cur_token + 1].parse::<u32>();
+                let lease_time = argv[cur_token + 2].parse::<f64>();
+                match (permits, max, lease_time) {
+                    (Ok(permits), Ok(max), Ok(num)) if permits > 0 && permits <= max =>
+                        result = process_sem_acquire_command(conn, iekey, permits, max, num),
+                    _ =>
+                        result = "CLIENT_ERROR bad command line format\r\n".to_string(),
+                }
+            },
+            CMDType::SemRelease => {
+                match argv.get(cur_token).map(|p| p.parse::<u32>()).transpose() {
+                    Ok(permits) if permits != Some(0) =>
+                        result = process_sem_release_command(conn, iekey, permits),
+                    _ =>
+                        result = "CLIENT_ERROR bad command line format\r\n".to_string(),
+                }
+            },
+            CMDType::Unlock => {
+                let token = options.get("owner").copied();
+                match options.get("fence").map(|f| f.parse::<u64>()).transpose() {
+                    Ok(fence) if token != Some("") =>
+                        result = process_unlock_command(conn, iekey, sub_key, fence, token),
+                    Ok(_) =>
+                        result = "CLIENT_ERROR bad command line format\r\n".to_string(),
+                    Err(_e) =>
+                        result = "CLIENT_ERROR bad command line format\r\n".to_string(),
+                }
+            },
+            CMDType::Publish => {
+                let msg = argv[cur_token].to_string();
+                // cur_token += 1;
+                result = process_publish_command(iekey, msg);
+            },
+            CMDType::Subscribe => {
+                result = format!("SUBSCRIBE {}\r\n", argc - 1);
+                loop {
+                    result += &process_subscribe_command(conn, iekey.clone());
+                    if cur_token < argc {
+                        iekey = argv[cur_token].to_string();
+                        cur_token += 1;
+                    } else {
+                        break;
+                    }
+                }
+                result += "END\r\n";
+            },
+            CMDType::Unsubscribe => {
+                result = process_unsubscribe_command(conn, iekey.clone())
+            },
+            _ => ()
+        }
+    }
+    return result;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    /* Every message pushed to a connection, with whether it was a late reply. */
+    static SENT: Mutex<Vec<(usize, String, bool)>> = Mutex::new(Vec::new());
+
+    /* Tests run in parallel on the same registry: each one uses connections and keys of its own. */
+    static NEXT_CONN: AtomicUsize = AtomicUsize::new(1);
+
+    /* Connections coming from the admin address; the others have no address. */
+    static ADMIN_CONNS: Mutex<Vec<usize>> = Mutex::new(Vec::new());
+
+    struct MockTransport;
+
+    impl Transport for MockTransport {
+        fn send(&self, msgs: Vec<(usize, String)>) {
+            SENT.lock().unwrap().extend(msgs.into_iter().map(|(conn, msg)| (conn, msg, false)));
+        }
+
+        fn reply(&self, conn: usize, msg: String) {
+            SENT.lock().unwrap().push((conn, msg, true));
+        }
+
+        fn client_ip(&self, conn: usize) -> Option<String> {
+            return ADMIN_CONNS.lock().unwrap().contains(&conn).then(|| "127.0.0.1".to_string());
+        }
+    }
+
+    fn conn() -> usize {
+        set_transport(Box::new(MockTransport));
+        return NEXT_CONN.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn admin() -> usize {
+        set_admins(vec!["127.0.0.1".to_string()]);
+        let conn = conn();
+        ADMIN_CONNS.lock().unwrap().push(conn);
+        return conn;
+    }
+
+    fn run(conn: usize, line: &str) -> String {
+        let args: Vec<&str> = line.split_whitespace().collect();
+        return process_command(conn, parse_command(&args), &args);
+    }
+
+    /* Takes the pushes to `conn` so far. */
+    fn pushed(conn: usize) -> Vec<String> {
+        return take_sent(conn, false);
+    }
+
+    /* Takes the late replies to `conn` so far. */
+    fn replied(conn: usize) -> Vec<String> {
+        return take_sent(conn, true);
+    }
+
+    fn take_sent(conn: usize, late: bool) -> Vec<String> {
+        let mut taken: Vec<String> = Vec::new();
+        SENT.lock().unwrap().retain(|(c, msg, reply)| {
+            if *c == conn && *reply == late {
+                taken.push(msg.clone());
+                return false;
+            }
+            return true;
+        });
+        return taken;
+    }
+
+    fn sleep(secs: f64) {
+        thread::sleep(Duration::from_secs_f64(secs));
+    }
+
+    #[test]
+    fn published_messages_reach_subscribers() {
+        let (a, b) = (conn(), conn());
+        assert_eq!(run(a, "subscribe t_ch t_ch2"), "SUBSCRIBE 2\r\nt_ch SUCCESS\r\nt_ch2 SUCCESS\r\nEND\r\n");
+        assert_eq!(run(b, "publish t_ch hello"), "PUBLISHED\r\n");
+        assert_eq!(pushed(a), vec!["CHANNEL t_ch hello\r\n"]);
+
+        assert_eq!(run(a, "unsubscribe t_ch"), "SUCCESS\r\n");
+        assert_eq!(run(a, "unsubscribe t_ch"), "NOT_SUBSCRIBED\r\n");
+        assert_eq!(run(b, "publish t_ch hello"), "PUBLISHED\r\n");
+        assert!(pushed(a).is_empty());
+        assert_eq!(run(b, "publish t_ch_missing hello"), "NOT_FOUND\r\n");
+    }
+
+    #[test]
+    fn lock_is_exclusive_until_unlocked() {
+        let (a, b) = (conn(), conn());
+        let fence = run(a, "lock t_lock 5");
+        assert!(fence.starts_with("OK "));
+        assert_eq!(run(a, "lock t_lock 5"), fence.replace("OK", "OWNED"));
+        assert!(run(b, "lock t_lock 5").starts_with("RETRY_LATER "));
+        assert_eq!(run(b, "unlock t_lock"), "NOT_OWNED\r\n");
+        assert_eq!(run(b, "subscribe t_lock"), "SUBSCRIBE 1\r\nt_lock TYPE_MISMATCH\r\nEND\r\n");
+
+        assert_eq!(run(a, "unlock t_lock"), "SUCCESS\r\n");
+        assert_eq!(pushed(b), vec!["UNLOCKED t_lock\r\n"]);
+        assert!(run(b, "lock t_lock 5").starts_with("OK "));
+    }
+
+    #[test]
+    fn sub_keys_are_separate_holds() {
+        let (a, b) = (conn(), conn());
+        assert!(run(a, "lock t_sub 1 5").starts_with("OK "));
+        assert_eq!(run(a, "unlock t_sub"), "NOT_OWNED\r\n");
+        assert!(run(b, "lock t_sub 2 5").starts_with("RETRY_LATER "));
+        assert_eq!(run(a, "unlock t_sub 1"), "SUCCESS\r\n");
+        assert_eq!(pushed(b), vec!["UNLOCKED t_sub [sub_key=2]\r\n"]);
+    }
+
+    #[test]
+    fn disconnect_releases_held_locks() {
+        let (a, b) = (conn(), conn());
+        assert!(run(a, "lock t_disc 5").starts_with("OK "));
+        assert!(run(b, "lock t_disc 5").starts_with("RETRY_LATER "));
+
+        unsubscribe_all(a);
+        assert_eq!(pushed(b), vec!["UNLOCKED t_disc\r\n"]);
+        assert!(run(b, "lock t_disc 5").starts_with("OK "));
+    }
+
+    fn fence_of(reply: &str) -> u64 {
+        return reply.split_whitespace().nth(1).unwrap().parse().unwrap();
+    }
+
+    #[test]
+    fn fences_increase_and_guard_unlock() {
+        let (a, b) = (conn(), conn());
+        let first = fence_of(&run(a, "lock t_fence 5"));
+        assert_eq!(run(a, "unlock t_fence"), "SUCCESS\r\n");
+
+        let second = fence_of(&run(b, "lock t_fence 5"));
+        assert!(second > first);
+        assert_eq!(run(b, &format!("unlock t_fence fence={}", first)), "NOT_OWNED\r\n");
+        assert_eq!(run(b, "unlock t_fence fence=x"), "CLIENT_ERROR bad command line format\r\n");
+        assert_eq!(run(b, &format!("unlock t_fence fence={}", second)), "SUCCESS\r\n");
+    }
+
+    #[test]
+    fn reentrant_lock_counts_holds() {
+        let (a, b) = (conn(), conn());
+        let fence = run(a, "lock t_reentrant 5 reentrant");
+        assert!(fence.starts_with("OK "));
+        assert_eq!(run(a, "lock t_reentrant 5 reentrant"), fence.replace("OK", "OWNED"));
+        assert_eq!(run(a, "lock t_reentrant 5"), fence.replace("OK", "OWNED"));
+
+        assert_eq!(run(a, "unlock t_reentrant"), "HELD 1\r\n");
+        assert!(run(b, "lock t_reentrant 5").starts_with("RETRY_LATER "));
+        assert_eq!(run(a, "unlock t_reentrant"), "SUCCESS\r\n");
+        assert_eq!(pushed(b), vec!["UNLOCKED t_reentrant\r\n"]);
+    }
+
+    #[test]
+    fn waiting_writer_keeps_new_readers_out() {
+        let (a, b, c) = (conn(), conn(), conn());
+        assert!(run(a, "rlock t_rw 5").starts_with("OK "));
+        assert!(run(b, "rlock t_rw 5").starts_with("OK "));
+        assert!(run(c, "wlock t_rw 5").starts_with("RETRY_LATER "));
+        assert!(run(a, "rlock t_rw_other 5").starts_with("OK "));
+        assert_eq!(run(a, "lock t_rw 5"), "TYPE_MISMATCH\r\n");
+
+        assert_eq!(run(a, "unlock t_rw"), "SUCCESS\r\n");
+        assert!(run(a, "rlock t_rw 5").starts_with("RETRY_LATER "));
+        assert_eq!(run(b, "unlock t_rw"), "SUCCESS\r\n");
+        assert_eq!(pushed(c), vec!["UNLOCKED t_rw\r\n"]);
+        assert!(run(c, "wlock t_rw 5").starts_with("OK "));
+        assert!(run(c, "rlock t_rw 5").starts_with("OK "));
+    }
+
+    #[test]
+    fn semaphore_hands_out_up_to_max_permits() {
+        let (a, b, c) = (conn(), conn(), conn());
+        assert!(run(a, "sem_acquire t_sem 2 3 5").starts_with("OK "));
+        assert!(run(b, "sem_acquire t_sem 1 3 5").starts_with("OK "));
+        assert!(run(c, "sem_acquire t_sem 1 3 5").starts_with("RETRY_LATER "));
+        assert_eq!(run(c, "sem_acquire t_sem 1 4 5"), "MAX_MISMATCH\r\n");
+
+        assert_eq!(run(a, "sem_release t_sem 1"), "HELD 1\r\n");
+        assert_eq!(pushed(c), vec!["UNLOCKED t_sem\r\n"]);
+        assert!(run(c, "sem_acquire t_sem 1 3 5").starts_with("OK "));
+        assert_eq!(run(c, "sem_release t_sem"), "SUCCESS\r\n");
+        assert_eq!(run(c, "sem_release t_sem"), "NOT_OWNED\r\n");
+    }
+
+    #[test]
+    fn semaphore_permits_never_overflow() {
+        let (a, b) = (conn(), conn());
+        assert!(run(a, "sem_acquire t_sem_max 1 4294967295 5").starts_with("OK "));
+        assert!(run(b, "sem_acquire t_sem_max 4294967295 4294967295 5").starts_with("RETRY_LATER "));
+        assert_eq!(run(b, "sem_acquire t_sem_max 3 2 5"), "CLIENT_ERROR bad command line format\r\n");
+    }
+
+    #[test]
+    fn disconnect_gives_semaphore_permits_back() {
+        let (a, b) = (conn(), conn());
+        assert!(run(a, "sem_acquire t_sem_disc 1 1 5").starts_with("OK "));
+        assert!(run(b, "sem_acquire t_sem_disc 1 1 5").starts_with("RETRY_LATER "));
+
+        unsubscribe_all(a);
+        assert_eq!(pushed(b), vec!["UNLOCKED t_sem_disc\r\n"]);
+        assert!(run(b, "sem_acquire t_sem_disc 1 1 5").starts_with("OK "));
+    }
+
+    #[test]
+    fn fair_lock_is_handed_over_in_arrival_order() {
+        let (a, b, c) = (conn(), conn(), conn());
+        assert!(run(a, "lock t_fair 5").starts_with("OK "));
+        assert!(run(b, "lock t_fair 5 fair").starts_with("RETRY_LATER "));
+        assert!(run(c, "lock t_fair 5 fair").starts_with("RETRY_LATER "));
+
+        assert_eq!(run(a, "unlock t_fair"), "SUCCESS\r\n");
+        let locked = pushed(b);
+        assert_eq!(locked.len(), 1);
+        assert!(locked[0].starts_with("LOCKED t_fair "));
+        assert!(pushed(c).is_empty());
+
+        assert_eq!(run(c, "unlock t_fair"), "DEQUEUED\r\n");
+        assert_eq!(run(b, "unlock t_fair"), "SUCCESS\r\n");
+        assert!(pushed(c).is_empty());
+    }
+
+    #[test]
+    fn disconnect_hands_a_fair_lock_to_the_queue() {
+        let (a, b) = (conn(), conn());
+        assert!(run(a, "lock t_disc_fair 5").starts_with("OK "));
+        assert!(run(b, "lock t_disc_fair 5 fair").starts_with("RETRY_LATER "));
+
+        unsubscribe_all(a);
+        let locked = pushed(b);
+        assert_eq!(locked.len(), 1);
+        assert!(locked[0].starts_with("LOCKED t_disc_fair "));
+    }
+
+    #[test]
+    fn parked_lock_is_answered_on_hand_off() {
+        let (a, b) = (conn(), conn());
+        assert!(run(a, "lock t_park 5").starts_with("OK "));
+        assert_eq!(run(b, "lock t_park 5 wait 2"), "");
+
+        assert_eq!(run(a, "unlock t_park"), "SUCCESS\r\n");
+        let reply = replied(b);
+        assert_eq!(reply.len(), 1);
+        assert!(reply[0].starts_with("OK "));
+        assert!(pushed(b).is_empty());
+    }
+
+    #[test]
+    fn parked_lock_times_out() {
+        let (a, b) = (conn(), conn());
+        assert!(run(a, "lock t_park_timeout 5").starts_with("OK "));
+        assert_eq!(run(b, "lock t_park_timeout 5 wait=0.1"), "");
+
+        sleep(0.4);
+        assert_eq!(replied(b), vec!["TIMEOUT\r\n"]);
+        assert_eq!(run(a, "unlock t_park_timeout"), "SUCCESS\r\n");
+        assert!(replied(b).is_empty());
+    }
+
+    #[test]
+    fn lease_expiry_releases_the_lock() {
+        let (a, b) = (conn(), conn());
+        assert!(run(a, "lock t_expiry 0.1").starts_with("OK "));
+        assert!(run(b, "lock t_expiry 5").starts_with("RETRY_LATER "));
+
+        sleep(0.4);
+        assert_eq!(pushed(a), vec!["LEASE_EXPIRED t_expiry\r\n"]);
+        assert_eq!(pushed(b), vec!["UNLOCKED t_expiry\r\n"]);
+        assert!(run(b, "lock t_expiry 5").starts_with("OK "));
+        assert_eq!(run(a, "unlock t_expiry"), "NOT_OWNED\r\n");
+    }
+
+    #[test]
+    fn renewed_lease_outlives_the_first_timer() {
+        let a = conn();
+        assert!(run(a, "lock t_renew 0.1").starts_with("OK "));
+        assert!(run(a, "lock t_renew 0.6").starts_with("OWNED "));
+
+        sleep(0.3);
+        assert!(pushed(a).is_empty());
+        sleep(0.6);
+        assert_eq!(pushed(a), vec!["LEASE_EXPIRED t_renew\r\n"]);
+    }
+
+    #[test]
+    fn expired_readers_are_told() {
+        let (a, b) = (conn(), conn());
+        assert!(run(a, "rlock t_rw_expiry 1 0.1").starts_with("OK "));
+        assert!(run(b, "wlock t_rw_expiry 5").starts_with("RETRY_LATER "));
+
+        sleep(0.4);
+        assert_eq!(pushed(a), vec!["LEASE_EXPIRED t_rw_expiry [sub_key=1]\r\n"]);
+        assert_eq!(pushed(b), vec!["UNLOCKED t_rw_expiry\r\n"]);
+    }
+
+    #[test]
+    fn relock_renews_only_the_owner() {
+        let (a, b) = (conn(), conn());
+        assert_eq!(run(a, "relock t_relock 5"), "NOT_FOUND\r\n");
+        let fence = fence_of(&run(a, "lock t_relock 0.2"));
+        assert_eq!(run(b, "relock t_relock 5"), "NOT_OWNED\r\n");
+        assert_eq!(run(a, "relock t_relock 5"), format!("OWNED {}\r\n", fence));
+
+        sleep(0.4);
+        assert!(pushed(a).is_empty());
+        assert!(run(a, "lockttl t_relock").starts_with("TTL 4."));
+        assert_eq!(run(b, "lockttl t_relock_none"), "NOT_FOUND\r\n");
+    }
+
+    #[test]
+    fn relock_does_not_retake_an_expired_lock() {
+        let a = conn();
+        assert!(run(a, "lock t_relock_late 0.1").starts_with("OK "));
+        sleep(0.3);
+        assert_eq!(run(a, "relock t_relock_late 5"), "NOT_FOUND\r\n");
+        assert_eq!(run(a, "lockttl t_relock_late"), "NOT_FOUND\r\n");
+    }
+
+    #[test]
+    fn lockinfo_lists_owner_and_waiters() {
+        let (a, b) = (conn(), conn());
+        let fence = fence_of(&run(a, "lock t_info 5"));
+        assert!(run(b, "lock t_info 5 fair").starts_with("RETRY_LATER "));
+
+        let info = run(b, "lockinfo t_info");
+        let lines: Vec<&str> = info.split("\r\n").collect();
+        assert_eq!(lines[0], format!("LOCKINFO t_info lock {}", fence));
+        assert!(lines[1].starts_with(&format!("OWNER {} - - 4.", a)));
+        assert_eq!(lines[2], format!("WAITER {} - - queued", b));
+        assert_eq!(lines[3], "END");
+        assert_eq!(run(a, "lockinfo t_info_none"), "NOT_FOUND\r\n");
+    }
+
+    #[test]
+    fn locks_filters_by_pattern() {
+        let a = conn();
+        assert!(run(a, "lock t_glob_1 5").starts_with("OK "));
+        assert!(run(a, "rlock t_glob_2 5").starts_with("OK "));
+        assert!(run(a, "lock t_globx 5").starts_with("OK "));
+        assert_eq!(run(a, "latch_create t_glob_3 1"), "CREATED\r\n");
+
+        let list = run(a, "locks t_glob_?");
+        let lines: Vec<&str> = list.split("\r\n").collect();
+        assert_eq!(lines[0], "LOCKS 2");
+        assert!(lines[1].starts_with("t_glob_1 lock 1 0 4."));
+        assert!(lines[2].starts_with("t_glob_2 rwlock 1 0 4."));
+        assert_eq!(lines[3], "END");
+        assert!(glob_match(b"a*c", b"abbc") && !glob_match(b"a*c", b"abbd"));
+    }
+
+    #[test]
+    fn mlock_takes_all_keys_or_none() {
+        let (a, b) = (conn(), conn());
+        assert!(run(b, "lock t_m2 5").starts_with("OK "));
+        assert_eq!(run(a, "mlock 5 t_m1 t_m2"), "CONTENDED t_m2\r\n");
+        assert_eq!(run(b, "lockinfo t_m1"), "NOT_FOUND\r\n");
+
+        assert_eq!(run(b, "unlock t_m2"), "SUCCESS\r\n");
+        assert_eq!(pushed(a), vec!["UNLOCKED t_m2\r\n"]);
+        let fences: Vec<u64> = run(a, "mlock 5 t_m1 t_m2 t_m1").trim_end()
+                                                               .split(' ')
+                                                               .skip(1)
+                                                               .map(|f| f.parse().unwrap())
+                                                               .collect();
+        assert_eq!(fences.len(), 2);
+        assert_eq!(run(b, "munlock t_m1 t_m2"), "NOT_OWNED t_m1 t_m2\r\n");
+        assert_eq!(run(a, "munlock t_m1 t_m2"), "SUCCESS\r\n");
+    }
+
+    #[test]
+    fn unlock_force_revokes_every_holder() {
+        let (admin, a, b, c) = (admin(), conn(), conn(), conn());
+        assert!(run(a, "rlock t_force 1 5").starts_with("OK "));
+        assert!(run(b, "rlock t_force 5").starts_with("OK "));
+        assert!(run(c, "wlock t_force 5").starts_with("RETRY_LATER "));
+
+        assert_eq!(run(a, "unlock_force t_force"), "NOT_ALLOWED\r\n");
+        assert_eq!(run(admin, "unlock_force t_force"), "SUCCESS\r\n");
+        assert_eq!(pushed(a), vec!["LOCK_REVOKED t_force [sub_key=1]\r\n"]);
+        assert_eq!(pushed(b), vec!["LOCK_REVOKED t_force\r\n"]);
+        assert_eq!(pushed(c), vec!["UNLOCKED t_force\r\n"]);
+        assert_eq!(run(admin, "unlock_force t_force"), "NOT_FOUND\r\n");
+    }
+
+    #[test]
+    fn lock_transfer_answers_a_parked_target() {
+        let (admin, a, b) = (admin(), conn(), conn());
+        let fence = fence_of(&run(a, "lock t_transfer 5"));
+        assert_eq!(run(b, "lock t_transfer 5 wait 5"), "");
+
+        assert_eq!(run(admin, "lock_transfer t_transfer 999999"), "NOT_FOUND_CONN\r\n");
+        let moved = fence_of(&run(admin, &format!("lock_transfer t_transfer {}", b)));
+        assert!(moved > fence);
+        assert_eq!(pushed(a), vec!["LOCK_REVOKED t_transfer\r\n"]);
+        assert_eq!(replied(b), vec![format!("OK {}\r\n", moved)]);
+        assert_eq!(run(a, "unlock t_transfer"), "NOT_OWNED\r\n");
+        assert_eq!(run(b, "unlock t_transfer"), "SUCCESS\r\n");
+
+        run(a, "lock t_transfer 5 owner=job-1");
+        assert_eq!(run(admin, &format!("lock_transfer t_transfer {}", b)), "TOKEN_BOUND\r\n");
+        assert_eq!(run(a, "unlock t_transfer owner=job-1"), "SUCCESS\r\n");
+    }
+
+    #[test]
+    fn token_bound_lock_belongs_to_the_token() {
+        let (a, b) = (conn(), conn());
+        let fence = fence_of(&run(a, "lock t_token 5 owner=job-1"));
+        assert!(run(b, "lock t_token 5").starts_with("RETRY_LATER "));
+        assert_eq!(run(b, "lock t_token 5 owner=job-1"), format!("OWNED {}\r\n", fence));
+        assert_eq!(run(a, "unlock t_token owner=job-2"), "NOT_OWNED\r\n");
+        assert_eq!(run(b, "unlock t_token owner=job-1"), "SUCCESS\r\n");
+        assert_eq!(run(a, "lock t_token 5 owner="), "CLIENT_ERROR bad command line format\r\n");
+    }
+
+    #[test]
+    fn disconnect_keeps_token_bound_locks() {
+        let (a, b, c) = (conn(), conn(), conn());
+        let fence = fence_of(&run(a, "lock t_keep 5 owner=job-1"));
+        unsubscribe_all(a);
+
+        assert!(run(c, "lock t_keep 5").starts_with("RETRY_LATER "));
+        assert!(run(c, "lockinfo t_keep").contains("OWNER - - - 4."));
+        assert_eq!(run(b, "relock t_keep 5 owner=job-1"), format!("OWNED {}\r\n", fence));
+        assert_eq!(run(b, "unlock t_keep owner=job-1"), "SUCCESS\r\n");
+        assert_eq!(pushed(c), vec!["UNLOCKED t_keep\r\n"]);
+    }
+
+    #[test]
+    fn token_moves_the_lock_to_the_presenting_connection() {
+        let (a, b, c) = (conn(), conn(), conn());
+        let fence = fence_of(&run(a, "lock t_rebind 5 owner=job-2 disconnect=release"));
+        assert_eq!(run(b, "relock t_rebind 5 owner=job-2"), format!("OWNED {}\r\n", fence));
+        unsubscribe_all(a);
+        assert!(run(c, "lock t_rebind 5").starts_with("RETRY_LATER "));
+        unsubscribe_all(b);
+        assert!(run(c, "lock t_rebind 5").starts_with("OK "));
+    }
+
+    #[test]
+    fn circular_wait_is_refused() {
+        let (a, b) = (conn(), conn());
+        assert!(run(a, "lock t_dl_1 5").starts_with("OK "));
+        assert!(run(b, "lock t_dl_2 5").starts_with("OK "));
+        assert!(run(a, "lock t_dl_2 5").starts_with("RETRY_LATER "));
+
+        assert_eq!(run(b, "lock t_dl_1 5"), "DEADLOCK t_dl_1 t_dl_2\r\n");
+        assert_eq!(run(a, "unlock t_dl_1"), "SUCCESS\r\n");
+        assert!(pushed(b).is_empty());
+    }
+
+    #[test]
+    fn given_up_retry_is_no_longer_a_wait() {
+        let (a, b) = (conn(), conn());
+        assert!(run(a, "lock t_stale1 5").starts_with("OK "));
+        assert!(run(b, "lock t_stale2 0.1").starts_with("OK "));
+        assert!(run(a, "lock t_stale2 5").starts_with("RETRY_LATER "));
+
+        /* `a` never comes back for t_stale2 after the lease and the grace period. */
+        sleep(0.1 + RETRY_GRACE.as_secs_f64() + 0.3);
+        assert!(run(b, "lock t_stale2 5").starts_with("OK "));
+        assert!(run(b, "lock t_stale1 5").starts_with("RETRY_LATER "));
+    }
+
+    #[test]
+    fn disconnect_policy_of_the_lock_applies() {
+        let (a, b, c) = (conn(), conn(), conn());
+        assert!(run(a, "lock t_policy_keep 5 disconnect=keep").starts_with("OK "));
+        assert!(run(b, "lock t_policy_grace 5 disconnect=0.2").starts_with("OK "));
+        assert!(run(c, "lock t_policy_grace 5").starts_with("RETRY_LATER "));
+        unsubscribe_all(a);
+        unsubscribe_all(b);
+
+        assert!(run(c, "lock t_policy_keep 5").starts_with("RETRY_LATER "));
+        assert!(pushed(c).is_empty());
+        sleep(0.4);
+        assert_eq!(pushed(c), vec!["UNLOCKED t_policy_grace\r\n"]);
+        assert!(run(c, "lock t_policy_grace 5").starts_with("OK "));
+        assert_eq!(run(c, "lock t_policy 5 disconnect=soon"), "CLIENT_ERROR bad command line format\r\n");
+    }
+
+    #[test]
+    fn latch_opens_at_zero() {
+        let (a, b) = (conn(), conn());
+        assert_eq!(run(a, "latch_wait t_latch"), "NOT_FOUND\r\n");
+        assert_eq!(run(a, "latch_create t_latch 2"), "CREATED\r\n");
+        assert_eq!(run(b, "latch_create t_latch 2"), "EXISTS\r\n");
+        assert_eq!(run(a, "latch_wait t_latch"), "WAITING 2\r\n");
+        assert_eq!(run(b, "subscribe t_latch"), "SUBSCRIBE 1\r\nt_latch TYPE_MISMATCH\r\nEND\r\n");
+
+        assert_eq!(run(b, "latch_countdown t_latch"), "COUNT 1\r\n");
+        assert!(pushed(a).is_empty());
+        assert_eq!(run(b, "latch_countdown t_latch 5"), "COUNT 0\r\n");
+        assert_eq!(pushed(a), vec!["LATCH_OPEN t_latch\r\n"]);
+        assert_eq!(run(b, "latch_wait t_latch"), "OPEN\r\n");
+
+        /* Dropped once its time as an open latch is up. */
+        if let Some(IEKState::Latch(l)) = IEK.lock().unwrap().get_mut("t_latch").map(|d| &mut d.state) {
+            l.open_until = Some(Instant::now());
+        }
+        on_timer("t_latch");
+        assert_eq!(run(b, "latch_wait t_latch"), "NOT_FOUND\r\n");
+    }
+
+    #[test]
+    fn barrier_opens_for_every_party() {
+        let (a, b, c) = (conn(), conn(), conn());
+        assert_eq!(run(a, "barrier_wait t_barrier 3"), "WAITING 1\r\n");
+        assert_eq!(run(b, "barrier_wait t_barrier 2"), "MAX_MISMATCH\r\n");
+        assert_eq!(run(b, "barrier_wait t_barrier 3"), "WAITING 2\r\n");
+        assert_eq!(run(c, "barrier_wait t_barrier 3"), "OPEN\r\n");
+        assert_eq!(pushed(a), vec!["BARRIER_OPEN t_barrier\r\n"]);
+        assert_eq!(pushed(b), vec!["BARRIER_OPEN t_barrier\r\n"]);
+
+        /* It starts over, and forgets a party that went away. */
+        assert_eq!(run(a, "barrier_wait t_barrier 3"), "WAITING 1\r\n");
+        unsubscribe_all(a);
+        assert_eq!(run(b, "barrier_wait t_barrier 2"), "WAITING 1\r\n");
+    }
+
+    #[test]
+    fn election_fails_over_in_arrival_order() {
+        let (a, b, c) = (conn(), conn(), conn());
+        let term = fence_of(&run(a, "elect t_elect n1 30"));
+        assert_eq!(run(b, "elect t_elect n2 30"), format!("FOLLOWER n1 {}\r\n", term));
+        assert_eq!(run(c, "elect t_elect n3 30"), format!("FOLLOWER n1 {}\r\n", term));
+        assert_eq!(run(a, "elect t_elect n1 30"), format!("ELECTED {}\r\n", term));
+
+        unsubscribe_all(a);
+        let pushes = pushed(b);
+        assert_eq!(pushes.len(), 1);
+        assert!(pushes[0].starts_with("LEADER t_elect n2 "));
+        let next: u64 = pushes[0].split_whitespace().last().unwrap().parse().unwrap();
+        assert!(next > term);
+        assert_eq!(pushed(c), pushes);
+        assert_eq!(run(c, "elect t_elect n3 30"), format!("FOLLOWER n2 {}\r\n", next));
+    }
+
+    #[test]
+    fn election_terms_count_per_group() {
+        let (a, b) = (conn(), conn());
+        assert_eq!(run(a, "elect t_term n1 30"), "ELECTED 1\r\n");
+        assert_eq!(run(b, "elect t_term n2 30"), "FOLLOWER n1 1\r\n");
+
+        /* Taking over on its own call, the new leader is answered and not told again. */
+        if let Some(IEKState::Election(e)) = IEK.lock().unwrap().get_mut("t_term").map(|d| &mut d.state) &&
+            let Some(l) = e.leader.as_mut() {
+            l.lease_time = Instant::now();
+        }
+        sleep(0.01);
+        assert_eq!(run(b, "elect t_term n2 30"), "ELECTED 2\r\n");
+        assert_eq!(pushed(a), vec!["LEASE_EXPIRED t_term\r\n"]);
+        assert!(pushed(b).is_empty());
+
+        /* A group that was dropped carries on from its last term. */
+        unsubscribe_all(b);
+        assert!(!IEK.lock().unwrap().contains_key("t_term"));
+        assert_eq!(run(a, "elect t_term n1 30"), "ELECTED 3\r\n");
+    }
+
+    #[test]
+    fn ratelimit_refills_over_time() {
+        let a = conn();
+        assert_eq!(run(a, "ratelimit t_rate 2 10"), "ALLOWED 1\r\n");
+        assert_eq!(run(a, "ratelimit t_rate 2 10"), "ALLOWED 0\r\n");
+        assert!(run(a, "ratelimit t_rate 2 10").starts_with("LIMITED 0."));
+        assert_eq!(run(a, "ratelimit t_rate 2 10 3"), "CLIENT_ERROR bad command line format\r\n");
+        sleep(0.25);
+        assert_eq!(run(a, "ratelimit t_rate 2 10 2"), "ALLOWED 0\r\n");
+        assert_eq!(run(a, "lock t_rate 5"), "TYPE_MISMATCH\r\n");
+    }
+
+    #[test]
+    fn wait_is_answered_by_notify_or_timeout() {
+        let (a, b, c) = (conn(), conn(), conn());
+        assert_eq!(run(a, "wait t_cond 5"), "");
+        assert_eq!(run(b, "wait t_cond 0.1"), "");
+        assert_eq!(run(c, "notify t_cond go"), "WOKEN 1\r\n");
+        assert_eq!(replied(a), vec!["NOTIFIED go\r\n"]);
+        sleep(0.3);
+        assert_eq!(replied(b), vec!["TIMEOUT\r\n"]);
+        assert_eq!(run(c, "notify t_cond all"), "WOKEN 0\r\n");
+    }
+
+    #[test]
+    fn sequences_resume_past_the_persisted_ceiling() {
+        let a = conn();
+        let path = std::env::temp_dir().join(format!("ingeoking-seq-{}", std::process::id()));
+        std::fs::write(&path, "t_seq 5000\n").unwrap();
+        assert!(set_sequence_file(path.to_str().unwrap()));
+        assert_eq!(run(a, "seq_next t_seq"), "RANGE 5000 5000\r\n");
+        assert_eq!(run(a, "seq_next t_seq 3"), "RANGE 5001 5003\r\n");
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "t_seq 6001\n");
+        assert_eq!(run(a, "seq_next t_seq 0"), "CLIENT_ERROR bad command line format\r\n");
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    fn run_data(conn: usize, line: &str, data: &[u8]) -> String {
+        let args: Vec<&str> = line.split_whitespace().collect();
+        let cmd_type = parse_command(&args);
+        assert_eq!(data_length(cmd_type, &args), Some(data.len() - 2));
+        return process_data_command(conn, cmd_type, &args, data);
+    }
+
+    #[test]
+    fn flight_followers_get_the_result() {
+        let (a, b, c) = (conn(), conn(), conn());
+        assert_eq!(run(a, "flight t_flight 5"), "LEADER\r\n");
+        assert_eq!(run(b, "flight t_flight 5"), "");
+        assert_eq!(run(c, "flight t_flight 5"), "");
+        assert_eq!(run_data(b, "flight_done t_flight 2", b"hi\r\n"), "NOT_OWNED\r\n");
+        assert_eq!(run_data(a, "flight_done t_flight 2", b"hi\r\n"), "WOKEN 2\r\n");
+        assert_eq!(replied(b), vec!["RESULT 2\r\nhi\r\n"]);
+        assert_eq!(replied(c), vec!["RESULT 2\r\nhi\r\n"]);
+
+        /* The next caller leads a new flight; its followers fail with it. */
+        assert_eq!(run(b, "flight t_flight 5"), "LEADER\r\n");
+        assert_eq!(run(c, "flight t_flight 5"), "");
+        unsubscribe_all(b);
+        assert_eq!(replied(c), vec!["FLIGHT_FAILED\r\n"]);
+        assert_eq!(run(c, "flight t_flight 5"), "LEADER\r\n");
+    }
+
+    #[test]
+    fn oversized_flight_result_is_refused() {
+        let a = conn();
+        assert_eq!(run(a, "flight t_flight_big 5"), "LEADER\r\n");
+        let line = format!("flight_done t_flight_big {}", MAX_DATA_LENGTH + 1);
+        let args: Vec<&str> = line.split_whitespace().collect();
+        assert_eq!(data_length(parse_command(&args), &args), Some(MAX_DATA_LENGTH + 1));
+        assert_eq!(run(a, &line), "CLIENT_ERROR data too large\r\n");
+        assert_eq!(run(a, &format!("flight_done t_flight_big {}", usize::MAX)), "CLIENT_ERROR data too large\r\n");
+        assert_eq!(run_data(a, "flight_done t_flight_big 2", b"\xff\xfe\r\n"), "CLIENT_ERROR bad data chunk\r\n");
+    }
+
+    #[test]
+    fn flight_followers_time_out_or_fail() {
+        let (a, b, c) = (conn(), conn(), conn());
+        assert_eq!(run(a, "flight t_flight_fail 5"), "LEADER\r\n");
+        assert_eq!(run(b, "flight t_flight_fail 0.1"), "");
+        assert_eq!(run(c, "flight t_flight_fail 5"), "");
+
+        sleep(0.4);
+        assert_eq!(replied(b), vec!["TIMEOUT\r\n"]);
+        unsubscribe_all(a);
+        assert_eq!(replied(c), vec!["FLIGHT_FAILED\r\n"]);
+    }
+}
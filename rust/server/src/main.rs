@@ -0,0 +1,320 @@
+use std::env;
+use std::fs;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::TcpListener;
+use std::os::unix::net::UnixListener;
+use std::collections::HashMap;
+use std::sync::{LazyLock, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{self, Sender};
+use std::process;
+use std::thread;
+
+use ingeoking_registry::{self as registry, CMDType, Transport};
+
+/* Same limit as the memcached ascii tokenizer. */
+const MAX_TOKENS: usize = 24;
+
+/* Longest request line read, about 24 tokens of 250 byte memcached keys. */
+const MAX_LINE: usize = 8192;
+
+static NEXT_CONN: AtomicUsize = AtomicUsize::new(1);
+
+struct Peer {
+    ip: String,
+    tx: Sender<String>,
+    /* Wakes the reader waiting on a parked command. */
+    resume: Sender<()>
+}
+
+static WRITERS: LazyLock<Mutex<HashMap<usize, Peer>>> = LazyLock::new(|| {
+    Mutex::new(HashMap::new())
+});
+
+struct ServerTransport;
+
+impl Transport for ServerTransport {
+    fn send(&self, msgs: Vec<(usize, String)>) {
+        let writers = WRITERS.lock().unwrap();
+        for (conn, msg) in msgs {
+            if let Some(peer) = writers.get(&conn) {
+                let _ = peer.tx.send(msg);
+            }
+        }
+    }
+
+    fn reply(&self, conn: usize, msg: String) {
+        let writers = WRITERS.lock().unwrap();
+        if let Some(peer) = writers.get(&conn) {
+            let _ = peer.tx.send(msg);
+            let _ = peer.resume.send(());
+        }
+    }
+
+    fn client_ip(&self, conn: usize) -> Option<String> {
+        return WRITERS.lock().unwrap().get(&conn).map(|peer| peer.ip.clone());
+    }
+
+    fn log(&self, msg: &str) {
+        eprintln!("{}", msg);
+    }
+}
+
+fn handle_conn<S>(reader: S, mut writer: S, ip: String)
+    where S: Read + Write + Send + 'static
+{
+    let conn = NEXT_CONN.fetch_add(1, Ordering::Relaxed);
+    let (tx, rx) = mpsc::channel::<String>();
+    let (resume, resumed) = mpsc::channel::<()>();
+    WRITERS.lock().unwrap().insert(conn, Peer { ip, tx: tx.clone(), resume });
+
+    /* Replies and pushes share one queue so they never interleave mid-line. */
+    let write_thread = thread::spawn(move || {
+        for msg in rx {
+            if writer.write_all(msg.as_bytes()).is_err() {
+                break;
+            }
+        }
+    });
+
+    let mut reader = BufReader::new(reader);
+    let mut buf: Vec<u8> = Vec::new();
+    loop {
+        buf.clear();
+        match reader.by_ref().take(MAX_LINE as u64).read_until(b'\n', &mut buf) {
+            Ok(0) | Err(_) => break,
+            Ok(_) => ()
+        }
+        if buf.len() == MAX_LINE && buf.last() != Some(&b'\n') {
+            /* The rest of the line is dropped unread, and the whole line refused. */
+            if !matches!(reader.skip_until(b'\n'), Ok(n) if n > 0) ||
+                tx.send("CLIENT_ERROR line too long\r\n".to_string()).is_err() {
+                break;
+            }
+            continue;
+        }
+
+        let line = String::from_utf8_lossy(&buf);
+        let line = line.trim_end_matches('\n').trim_end_matches('\r');
+        let args: Vec<&str> = line.split(' ')
+                                  .filter(|s| !s.is_empty())
+                                  .collect();
+        if args.len() == 1 && args[0] == "quit" {
+            break;
+        }
+
+        let cmd_type = registry::parse_command(&args);
+        let result = if args.len() > MAX_TOKENS {
+            /* Refused whole rather than cut short, so no key of an mlock goes missing. */
+            "CLIENT_ERROR too many tokens\r\n".to_string()
+        } else if cmd_type == CMDType::Unknown {
+            "ERROR unknown command\r\n".to_string()
+        } else if let Some(n) = registry::data_length(cmd_type, &args) && n > registry::MAX_DATA_LENGTH {
+            /* Swallowed like memcached does with a value too large, so the next line is a command. */
+            let skip = (n as u64).saturating_add(2);
+            if !matches!(io::copy(&mut reader.by_ref().take(skip), &mut io::sink()), Ok(copied) if copied == skip) {
+                break;
+            }
+            registry::process_command(conn, cmd_type, &args)
+        } else if let Some(n) = registry::data_length(cmd_type, &args) {
+            /* The data block follows the request line, like the value of a memcached set. */
+            let mut data = vec![0u8; n + 2];
+            if reader.read_exact(&mut data).is_err() {
+                break;
+            }
+            registry::process_data_command(conn, cmd_type, &args, &data)
+        } else {
+            registry::process_command(conn, cmd_type, &args)
+        };
+        /* A parked command holds back the next one, so replies stay in request order. */
+        if result.is_empty() {
+            if resumed.recv().is_err() {
+                break;
+            }
+            continue;
+        }
+        if tx.send(result).is_err() {
+            break;
+        }
+    }
+
+    WRITERS.lock().unwrap().remove(&conn);
+    registry::unsubscribe_all(conn);
+    drop(tx);
+    let _ = write_thread.join();
+}
+
+fn usage() -> ! {
+    eprintln!("usage: arcus-pubsub-server [-p <tcp port>] [-l <interface>] [-s <unix socket path>] \
+               [-A <admin ip>[,<admin ip>...]] [-D release|keep|<grace secs>] [-S <sequence file>]");
+    process::exit(1);
+}
+
+fn main() {
+    let mut port = "11211".to_string();
+    let mut interface = "0.0.0.0".to_string();
+    let mut socket_path: Option<String> = None;
+
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-p" => port = args.next().unwrap_or_else(|| usage()),
+            "-l" => interface = args.next().unwrap_or_else(|| usage()),
+            "-s" => socket_path = Some(args.next().unwrap_or_else(|| usage())),
+            "-A" => {
+                let ips = args.next().unwrap_or_else(|| usage());
+                registry::set_admins(ips.split(',').map(|ip| ip.to_string()).collect());
+            },
+            "-S" => {
+                let path = args.next().unwrap_or_else(|| usage());
+                if !registry::set_sequence_file(&path) {
+                    eprintln!("failed to read sequence file {}", path);
+                    process::exit(1);
+                }
+            },
+            "-D" => {
+                let policy = args.next().unwrap_or_else(|| usage());
+                if !registry::set_disconnect_policy(&policy) {
+                    usage();
+                }
+            },
+            _ => usage()
+        }
+    }
+
+    registry::set_transport(Box::new(ServerTransport));
+
+    /* Like memcached, a unix socket path disables the tcp listener. */
+    if let Some(path) = socket_path {
+        let _ = fs::remove_file(&path);
+        let listener = UnixListener::bind(&path).unwrap_or_else(|e| {
+            eprintln!("failed to listen on {}: {}", path, e);
+            process::exit(1);
+        });
+        for stream in listener.incoming().flatten() {
+            if let Ok(writer) = stream.try_clone() {
+                thread::spawn(move || handle_conn(stream, writer, "unix".to_string()));
+            }
+        }
+    } else {
+        let addr = format!("{}:{}", interface, port);
+        let listener = TcpListener::bind(&addr).unwrap_or_else(|e| {
+            eprintln!("failed to listen on {}: {}", addr, e);
+            process::exit(1);
+        });
+        for stream in listener.incoming().flatten() {
+            let _ = stream.set_nodelay(true);
+            let ip = stream.peer_addr().map_or("-".to_string(), |a| a.ip().to_string());
+            if let Ok(writer) = stream.try_clone() {
+                thread::spawn(move || handle_conn(stream, writer, ip));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::net::UnixStream;
+
+    /* Serves one end of a socket pair and returns the other end, read line by line. */
+    fn connect() -> (UnixStream, BufReader<UnixStream>) {
+        registry::set_transport(Box::new(ServerTransport));
+        let (client, server) = UnixStream::pair().unwrap();
+        let writer = server.try_clone().unwrap();
+        thread::spawn(move || handle_conn(server, writer, "127.0.0.1".to_string()));
+        let reader = BufReader::new(client.try_clone().unwrap());
+        return (client, reader);
+    }
+
+    fn read_line(reader: &mut BufReader<UnixStream>) -> String {
+        let mut line = String::new();
+        reader.read_line(&mut line).unwrap();
+        return line;
+    }
+
+    #[test]
+    fn commands_get_replies_and_pushes() {
+        let (mut a, mut a_reader) = connect();
+        let (mut b, mut b_reader) = connect();
+
+        a.write_all(b"subscribe srv_ch\r\n").unwrap();
+        assert_eq!(read_line(&mut a_reader), "SUBSCRIBE 1\r\n");
+        assert_eq!(read_line(&mut a_reader), "srv_ch SUCCESS\r\n");
+        assert_eq!(read_line(&mut a_reader), "END\r\n");
+
+        b.write_all(b"publish  srv_ch hello\n").unwrap();
+        assert_eq!(read_line(&mut b_reader), "PUBLISHED\r\n");
+        assert_eq!(read_line(&mut a_reader), "CHANNEL srv_ch hello\r\n");
+
+        b.write_all(b"nonsense\r\n").unwrap();
+        assert_eq!(read_line(&mut b_reader), "ERROR unknown command\r\n");
+    }
+
+    #[test]
+    fn quit_releases_what_the_connection_held() {
+        let (mut a, mut a_reader) = connect();
+        let (mut b, mut b_reader) = connect();
+
+        a.write_all(b"lock srv_lock 5\r\n").unwrap();
+        assert!(read_line(&mut a_reader).starts_with("OK "));
+        b.write_all(b"lock srv_lock 5\r\n").unwrap();
+        assert!(read_line(&mut b_reader).starts_with("RETRY_LATER "));
+
+        a.write_all(b"quit\r\n").unwrap();
+        assert_eq!(read_line(&mut a_reader), "");
+        assert_eq!(read_line(&mut b_reader), "UNLOCKED srv_lock\r\n");
+    }
+
+    #[test]
+    fn parked_command_holds_back_the_next_one() {
+        let (mut a, mut a_reader) = connect();
+        let (mut b, mut b_reader) = connect();
+
+        a.write_all(b"lock srv_park 5\r\n").unwrap();
+        assert!(read_line(&mut a_reader).starts_with("OK "));
+        b.write_all(b"lock srv_park 5 wait 5\r\npublish srv_park_ch x\r\n").unwrap();
+        a.write_all(b"unlock srv_park\r\n").unwrap();
+        assert_eq!(read_line(&mut a_reader), "SUCCESS\r\n");
+
+        assert!(read_line(&mut b_reader).starts_with("OK "));
+        assert_eq!(read_line(&mut b_reader), "NOT_FOUND\r\n");
+    }
+
+    #[test]
+    fn line_with_too_many_tokens_is_refused() {
+        let (mut a, mut a_reader) = connect();
+
+        let keys: Vec<String> = (0..MAX_TOKENS - 1).map(|i| format!("srv_many{}", i)).collect();
+        a.write_all(format!("mlock 5 {}\r\n", keys.join(" ")).as_bytes()).unwrap();
+        assert_eq!(read_line(&mut a_reader), "CLIENT_ERROR too many tokens\r\n");
+        a.write_all(format!("mlock 5 {}\r\n", keys[1..].join(" ")).as_bytes()).unwrap();
+        let reply = read_line(&mut a_reader);
+        assert_eq!(reply.split_whitespace().count(), MAX_TOKENS - 1);
+    }
+
+    #[test]
+    fn oversized_data_block_is_skipped() {
+        let (mut a, mut a_reader) = connect();
+
+        a.write_all(b"flight srv_flight 5\r\n").unwrap();
+        assert_eq!(read_line(&mut a_reader), "LEADER\r\n");
+        let n = registry::MAX_DATA_LENGTH + 1;
+        let mut request = format!("flight_done srv_flight {}\r\n", n).into_bytes();
+        request.extend(b"x".repeat(n));
+        request.extend(b"\r\nflight_done srv_flight 2\r\nok\r\n");
+        a.write_all(&request).unwrap();
+        assert_eq!(read_line(&mut a_reader), "CLIENT_ERROR data too large\r\n");
+        assert_eq!(read_line(&mut a_reader), "WOKEN 0\r\n");
+    }
+
+    #[test]
+    fn overlong_line_is_refused() {
+        let (mut a, mut a_reader) = connect();
+
+        a.write_all(format!("publish srv_long {}\r\n", "x".repeat(3 * MAX_LINE)).as_bytes()).unwrap();
+        assert_eq!(read_line(&mut a_reader), "CLIENT_ERROR line too long\r\n");
+        a.write_all(format!("publish srv_long {}\r\n", "x".repeat(MAX_LINE - 20)).as_bytes()).unwrap();
+        assert_eq!(read_line(&mut a_reader), "NOT_FOUND\r\n");
+    }
+}
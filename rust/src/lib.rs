@@ -1,51 +1,32 @@
 mod memcached;
 
 use std::ptr;
-use std::fs::File;
 use std::io::Write;
 use std::os::fd::FromRawFd;
 use std::net::TcpStream;
 use std::mem::ManuallyDrop;
 use std::collections::{HashMap, HashSet};
-use std::collections::hash_map::Entry;
 use std::sync::{LazyLock, Mutex};
-use std::time::{Instant, Duration};
-use std::ffi::CStr;
+use std::ffi::{CStr, CString};
 
 use libevent_sys::*;
 use libc::{c_void, c_char, c_int, c_short};
 
-use memcached::MemcachedConn;
+use memcached::{LibeventThread, MemcachedConn};
 use memcached::protocol_extension::*;
 
-#[derive(PartialEq)]
-#[allow(dead_code)]
-enum IEKType {
-    PubSub = 0,
-    Lock = 1
-}
-
-#[derive(PartialEq)]
-#[allow(dead_code)]
-enum CMDType {
-    Publish,
-    Subscribe,
-    Unsubscribe,
-    Lock,
-    Unlock,
-    Unknown
-}
-
-struct IEKData {
-    pub iek_type: IEKType,
-    pub sub_key: Option<i32>,
-    pub lease_time: Instant,
-    pub owner: Option<usize>,
-    pub waiters: HashMap<usize, HashSet<Option<i32>>>
-}
+use ingeoking_registry::{self as registry, CMDType, Transport};
 
 static mut SERVER_API: *mut SERVER_HANDLE_V1 = ptr::null_mut();
 
+/*
+ * Request tokens and data buffer of commands whose data block memcached is
+ * still reading, by connection: execute gets no tokens for them.
+ */
+static PENDING: LazyLock<Mutex<HashMap<usize, (Vec<String>, Vec<u8>)>>> = LazyLock::new(|| {
+    Mutex::new(HashMap::new())
+});
+
 static mut IEK_LOCK_DESCRIPTOR: EXTENSION_ASCII_PROTOCOL_DESCRIPTOR =
     EXTENSION_ASCII_PROTOCOL_DESCRIPTOR {
         get_name: get_name,
@@ -86,26 +67,94 @@ static mut IEK_SUB_DESCRIPTOR: EXTENSION_ASCII_PROTOCOL_DESCRIPTOR =
         next: std::ptr::null_mut()
     };
 
-static IEK: LazyLock<Mutex<HashMap<String, IEKData>>> = LazyLock::new(|| {
-    Mutex::new(HashMap::new())
-});
+static mut IEK_RWLOCK_DESCRIPTOR: EXTENSION_ASCII_PROTOCOL_DESCRIPTOR =
+    EXTENSION_ASCII_PROTOCOL_DESCRIPTOR {
+        get_name: get_name,
+        accept: accept_command,
+        execute: execute_command,
+        abort: abort_command,
+        cookie: &raw const IEK_RWLOCK_DESCRIPTOR as *const _ as *const c_void,
+        next: std::ptr::null_mut()
+    };
 
-static CONN: LazyLock<Mutex<HashMap<usize, HashSet<String>>>> = LazyLock::new(|| {
-    Mutex::new(HashMap::new())
-});
+static mut IEK_SEM_DESCRIPTOR: EXTENSION_ASCII_PROTOCOL_DESCRIPTOR =
+    EXTENSION_ASCII_PROTOCOL_DESCRIPTOR {
+        get_name: get_name,
+        accept: accept_command,
+        execute: execute_command,
+        abort: abort_command,
+        cookie: &raw const IEK_SEM_DESCRIPTOR as *const _ as *const c_void,
+        next: std::ptr::null_mut()
+    };
 
-macro_rules! is_lock_free {
-    ($d:expr) => {
-        $d.owner.is_none() ||
-        $d.lease_time < Instant::now()
+static mut IEK_INFO_DESCRIPTOR: EXTENSION_ASCII_PROTOCOL_DESCRIPTOR =
+    EXTENSION_ASCII_PROTOCOL_DESCRIPTOR {
+        get_name: get_name,
+        accept: accept_command,
+        execute: execute_command,
+        abort: abort_command,
+        cookie: &raw const IEK_INFO_DESCRIPTOR as *const _ as *const c_void,
+        next: std::ptr::null_mut()
+    };
+
+static mut IEK_ADMIN_DESCRIPTOR: EXTENSION_ASCII_PROTOCOL_DESCRIPTOR =
+    EXTENSION_ASCII_PROTOCOL_DESCRIPTOR {
+        get_name: get_name,
+        accept: accept_command,
+        execute: execute_command,
+        abort: abort_command,
+        cookie: &raw const IEK_ADMIN_DESCRIPTOR as *const _ as *const c_void,
+        next: std::ptr::null_mut()
+    };
+
+static mut IEK_RATELIMIT_DESCRIPTOR: EXTENSION_ASCII_PROTOCOL_DESCRIPTOR =
+    EXTENSION_ASCII_PROTOCOL_DESCRIPTOR {
+        get_name: get_name,
+        accept: accept_command,
+        execute: execute_command,
+        abort: abort_command,
+        cookie: &raw const IEK_RATELIMIT_DESCRIPTOR as *const _ as *const c_void,
+        next: std::ptr::null_mut()
+    };
+
+static mut IEK_SEQ_DESCRIPTOR: EXTENSION_ASCII_PROTOCOL_DESCRIPTOR =
+    EXTENSION_ASCII_PROTOCOL_DESCRIPTOR {
+        get_name: get_name,
+        accept: accept_command,
+        execute: execute_command,
+        abort: abort_command,
+        cookie: &raw const IEK_SEQ_DESCRIPTOR as *const _ as *const c_void,
+        next: std::ptr::null_mut()
+    };
+
+static mut IEK_SYNC_DESCRIPTOR: EXTENSION_ASCII_PROTOCOL_DESCRIPTOR =
+    EXTENSION_ASCII_PROTOCOL_DESCRIPTOR {
+        get_name: get_name,
+        accept: accept_command,
+        execute: execute_command,
+        abort: abort_command,
+        cookie: &raw const IEK_SYNC_DESCRIPTOR as *const _ as *const c_void,
+        next: std::ptr::null_mut()
     };
-}
 
 struct EventMessage {
     ev: *mut event,
-    message: String
+    conn: usize,
+    message: String,
+    /* Written from here on, after partial writes. */
+    offset: usize,
+    /* The late reply of a parked command: the connection is resumed once it is written. */
+    resume: bool
 }
 
+/*
+ * Connections whose command was parked with ENGINE_EWOULDBLOCK: memcached reads
+ * nothing more from them until notify_io_complete, so later commands stay behind.
+ */
+static PARKED: LazyLock<Mutex<HashSet<usize>>> = LazyLock::new(|| {
+    Mutex::new(HashSet::new())
+});
+
 #[allow(unused_variables)]
 unsafe extern "C" fn event_resp_cb(fd: i32, _events: i16, arg: *mut c_void) {
     let mut stream =  unsafe {
@@ -113,10 +162,29 @@ unsafe extern "C" fn event_resp_cb(fd: i32, _events: i16, arg: *mut c_void) {
     };
     let ev_msg = unsafe{ &mut *(arg as *mut EventMessage) };
 
-    if let Err(_e) = stream.write(ev_msg.message.as_bytes()) {
-        /* log: "Write failure" */
+    match stream.write(&ev_msg.message.as_bytes()[ev_msg.offset..]) {
+        Ok(n) if ev_msg.offset + n < ev_msg.message.len() => {
+            ev_msg.offset += n;
+            unsafe { event_add(ev_msg.ev, ptr::null()) };
+            return;
+        },
+        Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => {
+            unsafe { event_add(ev_msg.ev, ptr::null()) };
+            return;
+        },
+        Ok(_) if ev_msg.resume && PARKED.lock().unwrap().remove(&ev_msg.conn) => {
+            unsafe {
+                ((*(*SERVER_API).core).notify_io_complete)(ev_msg.conn as *const c_void,
+                                                           types::ENGINE_ERROR_CODE::ENGINE_SUCCESS);
+            }
+        },
+        Ok(_) => (),
+        Err(_e) => {
+            /* log: "Write failure" */
+        }
     }
 
+    let ev_msg = unsafe { Box::from_raw(arg as *mut EventMessage) };
     unsafe { event_free(ev_msg.ev) };
 }
 
@@ -125,39 +193,81 @@ extern "C" fn unsubscribe_all(cookie: *const c_void,
                               _type: callback::ENGINE_EVENT_TYPE,
                               event_data: *const c_void, cb_data: *const c_void)
 {
-    let ptr = cookie as usize;
-    let s: HashSet<String>;
-    {
-        let mut conn = CONN.lock().unwrap();
-        s = match conn.entry(ptr) {
-            Entry::Vacant(e) => HashSet::new(),
-            Entry::Occupied(e) => e.get().clone()
-        };
+    PENDING.lock().unwrap().remove(&(cookie as usize));
+    PARKED.lock().unwrap().remove(&(cookie as usize));
+    registry::unsubscribe_all(cookie as usize);
+    if let Some(mailbox) = MAILBOXES.lock().unwrap().get(&thread_of(cookie as usize)) {
+        mailbox.live.lock().unwrap().remove(&(cookie as usize));
+        mailbox.messages.lock().unwrap().retain(|(c, _, _)| *c != cookie as usize);
     }
+}
 
-    {
-        let mut iek = IEK.lock().unwrap();
-        for iekey in s {
-            match iek.get_mut(&iekey) {
-                Some(iekdata) => {
-                    iekdata.waiters.remove(&ptr);
-                    if iekdata.owner.is_none() ||
-                       (!iekdata.owner.is_none() && iekdata.owner.unwrap() == ptr) {
-                        iekdata.owner = None;
-                        if iekdata.waiters.is_empty() {
-                            iek.remove(&iekey);
-                        } else if iekdata.iek_type == IEKType::Lock {
-                            do_publish(iekdata, format!("UNLOCKED {}", iekey));
-                        }
-                    }
-                }
-                None => ()
-            }
+/*
+ * Messages for the connections of one worker thread. Pushes come from any
+ * thread, the registry's timer too, but the events of a connection are only
+ * added by its own worker: the pipe wakes it up to add them itself.
+ */
+struct Mailbox {
+    /* Connection, message and whether it is the late reply of a parked command. */
+    messages: Mutex<Vec<(usize, String, bool)>>,
+    /* Connections of the worker that ran a command and are still open. */
+    live: Mutex<HashSet<usize>>,
+    wake_fd: c_int
+}
+
+/* Mailboxes by worker thread. */
+static MAILBOXES: LazyLock<Mutex<HashMap<usize, &'static Mailbox>>> = LazyLock::new(|| {
+    Mutex::new(HashMap::new())
+});
+
+fn thread_of(c: usize) -> usize {
+    return unsafe { (*(c as *const MemcachedConn)).thread as usize };
+}
+
+/* Sets up the mailbox of the connection's worker; must run on that worker. */
+fn mailbox_for(c: usize) -> Option<&'static Mailbox> {
+    let thread = thread_of(c);
+    let mut mailboxes = MAILBOXES.lock().unwrap();
+    if let Some(mailbox) = mailboxes.get(&thread) {
+        return Some(mailbox);
+    }
+
+    let mut fds: [c_int; 2] = [0; 2];
+    if unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_NONBLOCK | libc::O_CLOEXEC) } != 0 {
+        return None;
+    }
+    let mailbox: &'static Mailbox = Box::leak(Box::new(Mailbox {
+        messages: Mutex::new(Vec::new()),
+        live: Mutex::new(HashSet::new()),
+        wake_fd: fds[1]
+    }));
+    unsafe {
+        let base = (*(thread as *const LibeventThread)).base;
+        let ev = event_new(base, fds[0], (EV_READ | EV_PERSIST) as c_short, Some(mailbox_cb),
+                           mailbox as *const Mailbox as *mut c_void);
+        event_add(ev, ptr::null());
+    }
+    mailboxes.insert(thread, mailbox);
+    return Some(mailbox);
+}
+
+#[allow(unused_variables)]
+unsafe extern "C" fn mailbox_cb(fd: i32, _events: i16, arg: *mut c_void) {
+    let mailbox = unsafe { &*(arg as *const Mailbox) };
+    let mut buf = [0u8; 64];
+    while unsafe { libc::read(fd, buf.as_mut_ptr() as *mut c_void, buf.len()) } > 0 {
+    }
+
+    let messages = std::mem::take(&mut *mailbox.messages.lock().unwrap());
+    let live = mailbox.live.lock().unwrap();
+    for (conn, msg, resume) in messages {
+        if live.contains(&conn) {
+            add_event_msg(conn, msg, resume);
         }
     }
 }
 
-fn add_event_msg(c: usize, msg: String) -> i32 {
+fn add_event_msg(c: usize, msg: String, resume: bool) {
     unsafe {
         let mconn = &*(c as *const MemcachedConn);
         let base = mconn.event.ev_base;
@@ -165,228 +275,107 @@ fn add_event_msg(c: usize, msg: String) -> i32 {
                     Some(event_resp_cb), std::ptr::null_mut());
         let arg = Box::new(EventMessage {
             ev: write_ev,
-            message: msg
+            conn: c,
+            message: msg,
+            offset: 0,
+            resume
         });
         event_assign(write_ev, base, mconn.sfd,
                      EV_WRITE as c_short, Some(event_resp_cb),
                      Box::into_raw(arg) as *mut c_void);
 
         event_add(write_ev, std::ptr::null_mut());
-
-        return (*mconn.thread).notify_send_fd;
-    }
-}
-
-fn do_publish(iekdata: &IEKData, msg: String) {
-    let mut notify_thread: HashSet<i32> = HashSet::new();
-
-    for (conn, s) in &iekdata.waiters {
-        for waiter in s {
-            let mut new_msg = msg.clone();
-            if !waiter.is_none() {
-                new_msg += &format!(" [sub_key={}]", waiter.unwrap());
-            }
-            new_msg += "\r\n";
-            notify_thread.insert(add_event_msg(*conn, new_msg));
-        }
-    }
-
-    for fd in notify_thread {
-        unsafe {
-            let mut wfd = ManuallyDrop::new(File::from_raw_fd(fd));
-            if let Err(_e) = write!(wfd, "{}", '\0') {
-                /* log: "Write failure" */
-            }
-        }
     }
 }
 
-#[allow(unused_variables)]
-fn process_publish_command(cookie: *const c_void, iekey: String, msg: String) -> String {
-    let mut result = "PUBLISHED\r\n".to_string();
+struct MemcachedTransport;
 
+/* Queues the messages for the workers of their connections, from whatever thread. */
+fn post(msgs: Vec<(usize, String)>, resume: bool) {
+    let mut woken: HashMap<usize, &'static Mailbox> = HashMap::new();
     {
-        let mut iek = IEK.lock().unwrap();
-        match iek.get_mut(&iekey) {
-            Some(iekdata) => {
-                if !is_lock_free!(iekdata) && iekdata.iek_type != IEKType::PubSub {
-                    return "TYPE_MISMATCH\r\n".to_string();
-                }
-                do_publish(iekdata, format!("CHANNEL {} {}", iekey, msg));
+        let mailboxes = MAILBOXES.lock().unwrap();
+        for (conn, msg) in msgs {
+            let thread = thread_of(conn);
+            if let Some(mailbox) = mailboxes.get(&thread) {
+                mailbox.messages.lock().unwrap().push((conn, msg, resume));
+                woken.insert(thread, *mailbox);
             }
-            None => result = "NOT_FOUND\r\n".to_string()
         }
     }
-    return result;
-}
-
-fn process_subscribe_command(cookie: *const c_void, iekey: String) -> String {
-    let ptr = cookie as usize;
-    let result = format!("{} SUCCESS\r\n", iekey);
-
-    {
-        let mut iek = IEK.lock().unwrap();
-        match iek.entry(iekey.clone()) {
-            Entry::Vacant(e) => {
-                e.insert(IEKData {
-                    iek_type: IEKType::PubSub,
-                    sub_key: None,
-                    lease_time: Instant::now(),
-                    owner: None,
-                    waiters: [(ptr, None)].into_iter()
-                                          .map(|(k, v)| (k, HashSet::from([v])))
-                                          .collect()
-                });
-            },
-            Entry::Occupied(mut e) => {
-                let data = e.get_mut();
-                if !is_lock_free!(data) && data.iek_type != IEKType::PubSub {
-                    return format!("{} TYPE_MISMATCH\r\n", iekey);
-                }
-                data.waiters.entry(ptr)
-                            .or_insert_with(HashSet::new)
-                            .insert(None);
-            }
-        }
-    }
-
-    {
-        let mut conn = CONN.lock().unwrap();
-        let s = match conn.entry(ptr) {
-            Entry::Vacant(e) => e.insert(HashSet::new()),
-            Entry::Occupied(e) => e.into_mut()
-        };
 
-        if !s.contains(&iekey) {
-            s.insert(iekey);
-        }
+    for mailbox in woken.values() {
+        /* A full pipe already has the worker woken up. */
+        unsafe { libc::write(mailbox.wake_fd, [0u8].as_ptr() as *const c_void, 1) };
     }
-
-    return result;
 }
 
-fn process_unsubscribe_command(cookie: *const c_void, iekey: String) -> String {
-    let ptr = cookie as usize;
-    let mut iek = IEK.lock().unwrap();
-    let mut result = "SUCCESS\r\n".to_string();
-
-    match iek.get_mut(&iekey) {
-        Some(iekdata) => {
-            if iekdata.waiters.remove(&ptr).is_none() {
-                result = "NOT_SUBSCRIBED\r\n".to_string();
-            }
-        },
-        _ => result = "NOT_FOUND_CHANNEL\r\n".to_string()
+impl Transport for MemcachedTransport {
+    fn send(&self, msgs: Vec<(usize, String)>) {
+        post(msgs, false);
     }
 
-    return result;
-}
+    fn reply(&self, conn: usize, msg: String) {
+        post(vec![(conn, msg)], true);
+    }
 
-fn process_lock_command(cookie: *const c_void, iekey: String, sub_key: Option<i32>, lease_time: f64) -> String {
-    let ptr = cookie as usize;
-    let mut result = String::new();
-    {
-        let cur_time = Instant::now();
-        let exp_time = cur_time + Duration::from_millis((lease_time * 1000.0) as u64);
-        let mut iek = IEK.lock().unwrap();
-        match iek.entry(iekey.clone()) {
-            Entry::Vacant(e) => {
-                e.insert(IEKData {
-                    iek_type: IEKType::Lock,
-                    sub_key: sub_key,
-                    lease_time: exp_time,
-                    owner: Some(cookie as usize),
-                    waiters: HashMap::new()
-                });
-                result = "OK\r\n".to_string();
-            },
-            Entry::Occupied(mut e) => {
-                let data = e.get_mut();
-                if data.iek_type != IEKType::Lock {
-                    return "TYPE_MISMATCH\r\n".to_string();
-                }
-                if data.owner.is_none() || data.lease_time < cur_time {
-                    data.owner = Some(ptr);
-                    data.sub_key = sub_key;
-                    data.lease_time = exp_time;
-                    if let Some(h) = data.waiters.get_mut(&ptr) {
-                        if h.contains(&sub_key) {
-                            h.remove(&sub_key);
-                        }
-                    }
-                    result = "OK\r\n".to_string();
-                } else if data.owner.unwrap() == ptr && data.sub_key == sub_key {
-                    data.lease_time = exp_time;
-                    result = "OWNED\r\n".to_string();
-                } else {
-                    data.waiters.entry(ptr)
-                                .or_insert_with(HashSet::new)
-                                .insert(sub_key);
-                    let remaining: f64 = (data.lease_time - cur_time).as_millis() as f64 / 1000.0;
-                    result += &format!("RETRY_LATER {:.3}\n", remaining);
-                }
+    fn client_ip(&self, conn: usize) -> Option<String> {
+        unsafe {
+            let ip = ((*(*SERVER_API).core).get_client_ip)(conn as *const c_void);
+            if ip.is_null() {
+                return None;
             }
+            return Some(CStr::from_ptr(ip).to_string_lossy().into_owned());
         }
     }
 
-    {
-        let mut conn = CONN.lock().unwrap();
-        let s = match conn.entry(ptr) {
-            Entry::Vacant(e) => e.insert(HashSet::new()),
-            Entry::Occupied(e) => e.into_mut()
+    fn log(&self, msg: &str) {
+        let msg = match CString::new(msg) {
+            Ok(msg) => msg,
+            Err(_e) => return
         };
-
-        if !s.contains(&iekey) {
-            s.insert(iekey);
+        unsafe {
+            let get_logger = match (*(*SERVER_API).log).get_logger {
+                Some(f) => f,
+                None => return
+            };
+            let logger = get_logger();
+            if let Some(log) = (*logger).log {
+                log(EXTENSION_LOG_LEVEL::EXTENSION_LOG_WARNING, ptr::null(),
+                    "ingeoking: %s\n\0".as_ptr() as *const c_char, msg.as_ptr());
+            }
         }
     }
-
-    return result;
 }
 
-#[allow(dead_code)]
-fn process_unlock_command(cookie: *const c_void, iekey: String, sub_key: Option<i32>) -> String {
-    let result: String;
-    let ptr = cookie as usize;
-
-    {
-        let mut iek = IEK.lock().unwrap();
-        match iek.get_mut(&iekey) {
-            Some(iekdata) => {
-                if iekdata.iek_type != IEKType::Lock {
-                    return "TYPE_MISMATCH\r\n".to_string();
-                }
-
-                if !is_lock_free!(iekdata) &&
-                    iekdata.owner.unwrap() == ptr && iekdata.sub_key == sub_key {
-                    iekdata.owner = None;
-                    if iekdata.waiters.is_empty() {
-                        iek.remove(&iekey);
-                    } else {
-                        do_publish(iekdata, format!("UNLOCKED {}", iekey));
-                    }
-                    result = "SUCCESS\r\n".to_string();
-                } else {
-                    result = "NOT_OWNED\r\n".to_string();
-                }
-            },
-            None => result = "NOT_FOUND\r\n".to_string()
-        }
+/*
+ * The extension config is `;` separated options, e.g. `admin=127.0.0.1,10.0.0.7;disconnect=1.5`.
+ * Fails if the sequence file cannot be read, rather than restart sequences from scratch.
+ */
+fn parse_config(config: *const c_char) -> bool {
+    if config.is_null() {
+        return true;
     }
-
-    {
-        let mut conn = CONN.lock().unwrap();
-        let s = match conn.entry(ptr) {
-            Entry::Vacant(e) => e.insert(HashSet::new()),
-            Entry::Occupied(e) => e.into_mut()
-        };
-
-        if  s.contains(&iekey) {
-            s.remove(&iekey);
+    let config = unsafe { CStr::from_ptr(config) }.to_string_lossy().into_owned();
+    for option in config.split(';') {
+        let option = option.trim();
+        if let Some(ips) = option.strip_prefix("admin=") {
+            registry::set_admins(ips.split(',').map(|ip| ip.trim().to_string()).collect());
+        } else if let Some(policy) = option.strip_prefix("disconnect=") {
+            registry::set_disconnect_policy(policy);
+        } else if let Some(path) = option.strip_prefix("seq_file=") &&
+            !registry::set_sequence_file(path) {
+            MemcachedTransport.log(&format!("cannot read sequence file {}", path));
+            return false;
         }
     }
+    return true;
+}
 
-    return result;
+fn collect_tokens(argc: c_int, argv: *mut token_t) -> Vec<String> {
+    return (0..argc as usize).map(|i| unsafe { CStr::from_ptr((*argv.add(i)).value) }
+                                          .to_string_lossy().into_owned())
+                             .collect();
 }
 
 #[allow(unused_variables)]
@@ -394,120 +383,97 @@ extern "C" fn get_name(cmd_cookie: *const c_void) -> *const c_char {
     return "ingeoking\0".as_ptr() as *const c_char;
 }
 
+fn descriptor_of(cmd_type: CMDType) -> *const c_void {
+    return match cmd_type {
+        CMDType::Lock | CMDType::Relock | CMDType::LockTTL | CMDType::MLock | CMDType::MUnlock =>
+            &raw const IEK_LOCK_DESCRIPTOR as *const _ as *const c_void,
+        CMDType::Unlock => &raw const IEK_UNLOCK_DESCRIPTOR as *const _ as *const c_void,
+        CMDType::RLock | CMDType::WLock =>
+            &raw const IEK_RWLOCK_DESCRIPTOR as *const _ as *const c_void,
+        CMDType::SemAcquire | CMDType::SemRelease =>
+            &raw const IEK_SEM_DESCRIPTOR as *const _ as *const c_void,
+        CMDType::LockInfo | CMDType::Locks =>
+            &raw const IEK_INFO_DESCRIPTOR as *const _ as *const c_void,
+        CMDType::UnlockForce | CMDType::LockTransfer =>
+            &raw const IEK_ADMIN_DESCRIPTOR as *const _ as *const c_void,
+        CMDType::LatchCreate | CMDType::LatchCountdown | CMDType::LatchWait | CMDType::BarrierWait |
+        CMDType::Elect | CMDType::Wait | CMDType::Notify | CMDType::Flight | CMDType::FlightDone =>
+            &raw const IEK_SYNC_DESCRIPTOR as *const _ as *const c_void,
+        CMDType::RateLimit => &raw const IEK_RATELIMIT_DESCRIPTOR as *const _ as *const c_void,
+        CMDType::SeqNext => &raw const IEK_SEQ_DESCRIPTOR as *const _ as *const c_void,
+        CMDType::Publish => &raw const IEK_PUB_DESCRIPTOR as *const _ as *const c_void,
+        CMDType::Subscribe | CMDType::Unsubscribe =>
+            &raw const IEK_SUB_DESCRIPTOR as *const _ as *const c_void,
+        CMDType::Unknown => ptr::null()
+    };
+}
+
 #[allow(unused_variables)]
 extern "C" fn accept_command(cmd_cookie: *const c_void, cookie: *mut c_void,
                              argc: c_int, argv: *mut token_t, ndata: *mut usize,
                              ptr: *mut *mut c_char) -> bool {
-    let op = unsafe { CStr::from_ptr((*argv).value)};
-    if cmd_cookie == &raw const IEK_LOCK_DESCRIPTOR as *const _ as *const c_void {
-        if argc >= 3 &&  argc <= 4 && op.to_str().unwrap_or("") == "lock" {
-            return true;
-        }
-    } else if cmd_cookie == &raw const IEK_UNLOCK_DESCRIPTOR as *const _ as *const c_void {
-        if argc >= 2 && argc <= 3 && op.to_str().unwrap_or("") == "unlock" {
-            return true;
-        }
-    } else if cmd_cookie == &raw const IEK_PUB_DESCRIPTOR as *const _ as *const c_void {
-        if argc == 3 && op.to_str().unwrap_or("") == "publish" {
-            return true;
-        }
-    } else if cmd_cookie == &raw const IEK_SUB_DESCRIPTOR as *const _ as *const c_void {
-        if argc >= 2 && (op.to_str().unwrap_or("") == "subscribe" ||
-                         op.to_str().unwrap_or("") == "unsubscribe") {
-            return true;
+    let tokens = collect_tokens(argc, argv);
+    let args: Vec<&str> = tokens.iter().map(|s| s.as_str()).collect();
+    let cmd_type = registry::parse_command(&args);
+    if cmd_type == CMDType::Unknown || descriptor_of(cmd_type) != cmd_cookie {
+        return false;
+    }
+
+    /*
+     * A block over MAX_DATA_LENGTH is left unread and execute_command refuses it.
+     * The heap buffer stays put when the Vec is moved into PENDING.
+     */
+    if let Some(n) = registry::data_length(cmd_type, &args) && n <= registry::MAX_DATA_LENGTH {
+        let mut data = vec![0u8; n + 2];
+        unsafe {
+            *ndata = data.len();
+            *ptr = data.as_mut_ptr() as *mut c_char;
         }
+        PENDING.lock().unwrap().insert(cookie as usize, (tokens, data));
     }
-    return false;
+    return true;
 }
+
 #[allow(unused_variables)]
 extern "C" fn execute_command(cmd_cookie: *const c_void, cookie: *const c_void,
     argc: c_int, argv: *mut token_t,
     response_handler: ResponseHandler) -> bool {
-    let mut cur_token: usize = 1;
-    let mut iekey = unsafe { CStr::from_ptr((*argv.add(cur_token)).value) }.to_string_lossy().into_owned();
-    let mut result  = "ERROR unknown command\r\n".to_string();
-    cur_token += 1;
-
-    let mut cmd_type = CMDType::Unknown;
-    if cmd_cookie == &raw const IEK_LOCK_DESCRIPTOR as *const _ as *const c_void {
-        cmd_type = CMDType::Lock;
-    } else if cmd_cookie == &raw const IEK_UNLOCK_DESCRIPTOR as *const _ as *const c_void {
-        cmd_type = CMDType::Unlock;
-    } else if cmd_cookie == &raw const IEK_PUB_DESCRIPTOR as *const _ as *const c_void {
-        cmd_type = CMDType::Publish;
-    } else if cmd_cookie == &raw const IEK_SUB_DESCRIPTOR as *const _ as *const c_void {
-        let first_str = unsafe { CStr::from_ptr((*argv).value) }.to_string_lossy().chars().next();
-        match first_str {
-            Some('s') => cmd_type = CMDType::Subscribe,
-            Some('u') => cmd_type = CMDType::Unsubscribe,
-            _ => cmd_type = CMDType::Unknown
-        }
+    if let Some(mailbox) = mailbox_for(cookie as usize) {
+        mailbox.live.lock().unwrap().insert(cookie as usize);
     }
-
-
-    let mut sub_key: Option<i32> = None;
-    let mut is_success = true;
-    if (cmd_type == CMDType::Lock && (argc - cur_token as c_int) == 2) ||
-       (cmd_type == CMDType::Unlock && (argc - cur_token as c_int) == 1) {
-        let str_sub_key = unsafe { CStr::from_ptr((*argv.add(cur_token)).value) };
-        let cvt_sub_key: Result<i32, _> = str_sub_key.to_str().unwrap_or("").parse();
-        cur_token += 1;
-        match cvt_sub_key {
-            Ok(num) => sub_key = Some(num),
-            Err(e) => {
-                is_success = false;
-                result = "CLIENT_ERROR bad command line format\r\n".to_string();
-            }
-        }
+    let result: String;
+    if argc == 0 {
+        /* The data block of a command accept asked memcached to read. */
+        let (tokens, data) = match PENDING.lock().unwrap().remove(&(cookie as usize)) {
+            Some(pending) => pending,
+            None => return false
+        };
+        let args: Vec<&str> = tokens.iter().map(|s| s.as_str()).collect();
+        let cmd_type = registry::parse_command(&args);
+        result = registry::process_data_command(cookie as usize, cmd_type, &args, &data);
+    } else {
+        let tokens = collect_tokens(argc, argv);
+        let args: Vec<&str> = tokens.iter().map(|s| s.as_str()).collect();
+        let cmd_type = registry::parse_command(&args);
+        result = registry::process_command(cookie as usize, cmd_type, &args);
     }
-
-    if is_success {
-        match cmd_type {
-            CMDType::Lock => {
-                let str_time = unsafe { CStr::from_ptr((*argv.add(cur_token)).value) };
-                let lease_time: Result<f64, _> = str_time.to_str().unwrap_or("").parse();
-                // cur_token += 1;
-                match lease_time {
-                    Ok(num) =>
-                        result = process_lock_command(cookie, iekey, sub_key, num),
-                    Err(e) =>
-                        result = "CLIENT_ERROR bad command line format\r\n".to_string(),
-                }
-            },
-            CMDType::Unlock => {
-                result = process_unlock_command(cookie, iekey, sub_key);
-            },
-            CMDType::Publish => {
-                let msg = unsafe { CStr::from_ptr((*argv.add(cur_token)).value) }.to_string_lossy().into_owned();
-                // cur_token += 1;
-                result = process_publish_command(cookie, iekey, msg);
-            },
-            CMDType::Subscribe => {
-                result = format!("SUBSCRIBE {}\r\n", argc - 1);
-                loop {
-                    result += &process_subscribe_command(cookie, iekey.clone());
-                    if cur_token < argc as usize {
-                        iekey = unsafe { CStr::from_ptr((*argv.add(cur_token)).value) }.to_string_lossy().into_owned();
-                        cur_token += 1;
-                    } else {
-                        break;
-                    }
-                }
-                result += "END\r\n";
-            },
-            CMDType::Unsubscribe => {
-                result = process_unsubscribe_command(cookie, iekey.clone())
-            },
-            _ => ()
-
-        }
+    if result.is_empty() {
+        /*
+         * Parked, e.g. `lock ... wait`: the reply is written later as an event
+         * message, and memcached leaves the connection alone until then.
+         */
+        PARKED.lock().unwrap().insert(cookie as usize);
+        unsafe { (*(cookie as *mut MemcachedConn)).ewouldblock = true };
+        return true;
     }
     return unsafe { response_handler(cookie, result.len() as i32, result.as_ptr() as *const c_char) };
 }
 
 #[allow(unused_variables)]
 extern "C" fn abort_command(cmd_cookie: *const c_void, cookie: *const c_void) {
-
+    PENDING.lock().unwrap().remove(&(cookie as usize));
+    PARKED.lock().unwrap().remove(&(cookie as usize));
+    registry::abort_command(cookie as usize);
 }
 
 #[unsafe(no_mangle)]
@@ -551,6 +517,60 @@ pub extern "C" fn memcached_extensions_initialize(
         return EXTENSION_ERROR_CODE::EXTENSION_FATAL;
     }
 
+    if unsafe { !((*extension).register_extension)(
+                    extension_type_t::EXTENSION_ASCII_PROTOCOL,
+                    &raw mut IEK_RWLOCK_DESCRIPTOR as *mut c_void) }
+    {
+        return EXTENSION_ERROR_CODE::EXTENSION_FATAL;
+    }
+
+    if unsafe { !((*extension).register_extension)(
+                    extension_type_t::EXTENSION_ASCII_PROTOCOL,
+                    &raw mut IEK_SEM_DESCRIPTOR as *mut c_void) }
+    {
+        return EXTENSION_ERROR_CODE::EXTENSION_FATAL;
+    }
+
+    if unsafe { !((*extension).register_extension)(
+                    extension_type_t::EXTENSION_ASCII_PROTOCOL,
+                    &raw mut IEK_INFO_DESCRIPTOR as *mut c_void) }
+    {
+        return EXTENSION_ERROR_CODE::EXTENSION_FATAL;
+    }
+
+    if unsafe { !((*extension).register_extension)(
+                    extension_type_t::EXTENSION_ASCII_PROTOCOL,
+                    &raw mut IEK_ADMIN_DESCRIPTOR as *mut c_void) }
+    {
+        return EXTENSION_ERROR_CODE::EXTENSION_FATAL;
+    }
+
+    if unsafe { !((*extension).register_extension)(
+                    extension_type_t::EXTENSION_ASCII_PROTOCOL,
+                    &raw mut IEK_SYNC_DESCRIPTOR as *mut c_void) }
+    {
+        return EXTENSION_ERROR_CODE::EXTENSION_FATAL;
+    }
+
+    if unsafe { !((*extension).register_extension)(
+                    extension_type_t::EXTENSION_ASCII_PROTOCOL,
+                    &raw mut IEK_RATELIMIT_DESCRIPTOR as *mut c_void) }
+    {
+        return EXTENSION_ERROR_CODE::EXTENSION_FATAL;
+    }
+
+    if unsafe { !((*extension).register_extension)(
+                    extension_type_t::EXTENSION_ASCII_PROTOCOL,
+                    &raw mut IEK_SEQ_DESCRIPTOR as *mut c_void) }
+    {
+        return EXTENSION_ERROR_CODE::EXTENSION_FATAL;
+    }
+
+    if !parse_config(config) {
+        return EXTENSION_ERROR_CODE::EXTENSION_FATAL;
+    }
+    registry::set_transport(Box::new(MemcachedTransport));
+
     let cb = unsafe { (*SERVER_API).callback };
     unsafe { ((*cb).register_callback)(ptr::null_mut(), callback::ENGINE_EVENT_TYPE::ON_DISCONNECT,
                                        unsubscribe_all, ptr::null()) };